@@ -0,0 +1,45 @@
+use std::hash::Hasher;
+
+/// FNV-1a, which unlike `DefaultHasher` is specified and so stays comparable
+/// with the hashes earlier builds wrote to a kernel's manifest.
+struct Fnv1a(u64);
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Hashes every input that affects a kernel's PTX, in order. The bytes are fed
+/// in directly, since how `str`'s `Hash` impl feeds them is unspecified; each
+/// is followed by its length, so that moving text between inputs changes the
+/// hash.
+pub(super) fn kernel_hash<'a>(inputs: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hasher = Fnv1a(0xcbf29ce484222325);
+    for input in inputs {
+        hasher.write(input.as_bytes());
+        hasher.write(&(input.len() as u64).to_le_bytes());
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// The `rustc --version` of the toolchain kernels are built with, or an empty
+/// string if it cannot be queried (the build itself then reports why).
+pub(super) fn toolchain_version(channel: &str) -> String {
+    std::process::Command::new("rustc")
+        .arg(format!("+{channel}"))
+        .arg("--version")
+        .env("RUSTUP_AUTO_INSTALL", "0")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default()
+}
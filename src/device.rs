@@ -0,0 +1,96 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+use proc_macro2::TokenStream;
+use quote::ToTokens;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{compile_error, OrCompileError};
+
+/// A `#[spindle::device]` item as saved, with the names of the items it uses.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Saved {
+    item: String,
+    uses: Vec<String>,
+}
+
+/// `#[spindle::device]` items are saved under the kernel workspace, where
+/// kernels expanded after them look them up by name.
+fn item_path(home: &Path, name: &str) -> PathBuf {
+    home.join("device-items").join(name).with_extension("json")
+}
+
+pub(super) fn save(home: &Path, name: &str, item: &TokenStream, uses: &[syn::Ident]) -> Result<(), TokenStream> {
+    let path = item_path(home, name);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .or_compile_error(item, format_args!("failed to create `{}`", dir.display()))?;
+    }
+    let saved = Saved {
+        item: item.to_string(),
+        uses: uses.iter().map(ToString::to_string).collect(),
+    };
+    // skip the write if unchanged, so that reruns do not touch the workspace
+    if load(&path).is_some_and(|old| old.eq(&saved)) {
+        return Ok(())
+    }
+    let json = serde_json::to_string_pretty(&saved)
+        .or_compile_error(item, format_args!("failed to serialize `{}`", path.display()))?;
+    std::fs::write(&path, json)
+        .or_compile_error(item, format_args!("failed to write `{}`", path.display()))
+}
+
+fn load(path: &Path) -> Option<Saved> {
+    let json = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+/// Fails unless an item was saved as `name`, for the kernel whose use of it is `tokens`.
+pub(super) fn require(home: &Path, name: &str, tokens: &TokenStream) -> Result<(), TokenStream> {
+    match item_path(home, name).exists() {
+        true => Ok(()),
+        false => {
+            let msg = format!("`{name}` is not a `#[spindle::device]` item declared before this kernel");
+            Err(compile_error(tokens, msg))
+        },
+    }
+}
+
+/// The struct saved as `name`, which the kernel crate of the kernel returning
+/// it (as `tokens`) copies.
+pub(super) fn saved_struct(home: &Path, name: &str, tokens: &TokenStream) -> Result<syn::ItemStruct, TokenStream> {
+    require(home, name, tokens)?;
+    let saved = load(&item_path(home, name))
+        .ok_or_else(|| compile_error(tokens, format_args!("failed to read the saved `{name}`")))?;
+    syn::parse_str(&saved.item)
+        .or_compile_error(tokens, format_args!("failed to parse the saved `{name}`"))
+}
+
+/// The saved items a kernel `uses`, and those they use in turn, in the order
+/// they are first named.
+pub(super) fn collect(home: &Path, uses: &[syn::Ident]) -> Result<TokenStream, TokenStream> {
+    let mut items = TokenStream::new();
+    let mut seen = HashSet::new();
+    let mut names: VecDeque<syn::Ident> = uses.iter().cloned().collect();
+    while let Some(name) = names.pop_front() {
+        let key = name.to_string();
+        if !seen.insert(key.clone()) {
+            continue
+        }
+        let tokens = name.to_token_stream();
+        require(home, &key, &tokens)?;
+        let saved = load(&item_path(home, &key))
+            .ok_or_else(|| compile_error(&tokens, format_args!("failed to read the saved `{key}`")))?;
+        let item: TokenStream = saved.item.parse()
+            .or_compile_error(&tokens, format_args!("failed to parse the saved `{key}`"))?;
+        for used in &saved.uses {
+            // an item's own uses are reported where the kernel names it
+            let mut used: syn::Ident = syn::parse_str(used)
+                .or_compile_error(&tokens, format_args!("failed to parse the saved `{key}`"))?;
+            used.set_span(name.span());
+            names.push_back(used);
+        }
+        items.extend(item);
+    }
+    Ok(items)
+}
@@ -1,6 +1,9 @@
-// use std::fmt::Display;
+use std::fmt::Display;
 
 use proc_macro2::TokenStream;
+use serde::Deserialize;
+
+use crate::source_map::SourceMap;
 
 // use proc_macro2::{TokenStream, Ident};
 // use syn::Generics;
@@ -24,38 +27,135 @@ use proc_macro2::TokenStream;
 //     }
 // }
 
-pub(super) trait NaivelyTokenize: Sized + ToString {
-    fn naively_tokenize(self) -> TokenStream {
-        let s = self.to_string();
-        println!("{s}");
-        quote::quote! { #s }
-        // panic!("{}", self.to_string());
+/// Turns a failure while preparing or building a kernel into a `compile_error!`
+/// spanned over `tokens`, usually the signature of the annotated function.
+pub(super) fn compile_error(tokens: &TokenStream, msg: impl Display) -> TokenStream {
+    syn::Error::new_spanned(tokens, msg).to_compile_error()
+}
+
+pub(super) trait OrCompileError<T> {
+    fn or_compile_error(self, tokens: &TokenStream, context: impl Display) -> Result<T, TokenStream>;
+}
+
+impl<T, E: Display> OrCompileError<T> for Result<T, E> {
+    fn or_compile_error(self, tokens: &TokenStream, context: impl Display) -> Result<T, TokenStream> {
+        self.map_err(|err| compile_error(tokens, format!("{context}: {err}")))
     }
 }
 
-impl NaivelyTokenize for std::io::Error {}
-impl NaivelyTokenize for serde_json::Error {}
-impl NaivelyTokenize for String {
-    fn naively_tokenize(self) -> TokenStream {
-        println!("{self}");
-        return quote::quote! { "kernel compile error: see terminal" };    
-        // todo!("sanitize #self")
+/// The subset of cargo's `--message-format=json` output that spindle reads.
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<Diagnostic>,
+}
+
+#[derive(Deserialize)]
+pub(super) struct Diagnostic {
+    message: String,
+    level: String,
+    spans: Vec<DiagnosticSpan>,
+    children: Vec<Diagnostic>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DiagnosticSpan {
+    file_name: String,
+    byte_start: usize,
+    byte_end: usize,
+    is_primary: bool,
+    label: Option<String>,
+}
+
+/// Collects the rustc diagnostics from cargo's json stdout, skipping the
+/// artifact and build-script messages.
+pub(super) fn diagnostics(stdout: &[u8]) -> Vec<Diagnostic> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(line).ok())
+        .filter(|message| message.reason.eq("compiler-message"))
+        .filter_map(|message| message.message)
+        .collect()
+}
+
+/// The human-readable text rustc would have printed for `diagnostics`.
+pub(super) fn rendered(diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter()
+        .filter_map(|diagnostic| diagnostic.rendered.as_deref())
+        .collect()
+}
+
+/// Turns the errors rustc reported while building a kernel crate into
+/// `compile_error!`s. Errors whose primary span lies in `src/device.rs` are
+/// spanned over the matching user tokens through `source_map`, the rest over
+/// `tokens`. Returns `None` if rustc reported no errors, i.e. cargo itself failed.
+pub(super) fn diagnostics_error(
+    diagnostics: &[Diagnostic],
+    source_map: &SourceMap,
+    tokens: &TokenStream,
+) -> Option<TokenStream> {
+    let errors = diagnostics.iter()
+        .filter(|diagnostic| diagnostic.level.eq("error"))
+        .filter_map(|diagnostic| {
+            let primary = diagnostic.spans.iter().find(|span| span.is_primary);
+            match primary {
+                Some(span) if span.file_name.ends_with("src/device.rs") => {
+                    let spanned = source_map.spanned(span.byte_start, span.byte_end)
+                        .unwrap_or_else(|| tokens.clone());
+                    let mut msg = diagnostic.message.clone();
+                    if let Some(label) = span.label.as_ref().filter(|label| !label.is_empty()) {
+                        msg = format!("{msg}\n{label}");
+                    }
+                    for child in &diagnostic.children {
+                        msg = format!("{msg}\n{}: {}", child.level, child.message);
+                    }
+                    Some(syn::Error::new_spanned(spanned, msg))
+                },
+                // summaries like "aborting due to 2 previous errors" restate the others
+                None if diagnostic.message.starts_with("aborting due to") => None,
+                _ => {
+                    let msg = diagnostic.rendered.as_deref().unwrap_or(&diagnostic.message);
+                    Some(syn::Error::new_spanned(tokens, format!("kernel crate: {}", msg.trim_end())))
+                },
+            }
+        });
+    errors
+        .reduce(|mut errors, error| {
+            errors.combine(error);
+            errors
+        })
+        .map(|errors| errors.to_compile_error())
+}
+
+/// Drops the SGR escape sequences cargo emits under `color = "always"`, which
+/// would otherwise show up verbatim in `compile_error!` messages.
+pub(super) fn strip_ansi(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().take_while(|c| !c.is_ascii_alphabetic()).for_each(drop);
+        } else {
+            stripped.push(c);
+        }
     }
+    stripped
 }
 
 pub(super) fn command_output_result(output: std::process::Output) -> Result<String, String> {
     let msg = match (&output.stderr, &output.stdout) {
         (err, out) if !err.is_empty() && !out.is_empty() => {
-            format!("{}\n{}", String::from_utf8_lossy(&err).trim_end(), String::from_utf8_lossy(&out).trim_start())
+            format!("{}\n{}", String::from_utf8_lossy(err).trim_end(), String::from_utf8_lossy(out).trim_start())
         },
         (err, _) if !err.is_empty() => {
-            String::from_utf8_lossy(&err).to_string()
+            String::from_utf8_lossy(err).to_string()
         },
         (_, out) if !out.is_empty() => {
-            String::from_utf8_lossy(&out).to_string()
+            String::from_utf8_lossy(out).to_string()
         },
         _ => {
-            String::from_utf8_lossy(&output.status.to_string().as_bytes()).to_string()
+            String::from_utf8_lossy(output.status.to_string().as_bytes()).to_string()
         },
     };
     if output.status.success() {
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::path::PathBuf;
 
 use proc_macro2::TokenStream;
@@ -5,17 +6,59 @@ use quote::ToTokens;
 use serde::{Deserialize, Serialize};
 use syn::parse_macro_input;
 
-use crate::error::{NaivelyTokenize, command_output_result};
+use crate::error::{compile_error, command_output_result, diagnostics, diagnostics_error, rendered, strip_ansi, OrCompileError};
+use crate::source_map::SourceMap;
 
+mod cache;
+mod device;
 mod error;
+mod map;
 mod parse;
 mod range;
+mod reduce;
+mod scan;
+mod source_map;
+#[cfg(test)]
+mod tests;
+mod toolchain;
 
 #[proc_macro_attribute]
 pub fn basic_range(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let attr = parse_macro_input!(attr as RangeAttributes);
     let item = parse_macro_input!(item as RangeFn);
-    let result = emit_range_kernel(attr, item);
+    let result = emit_range_kernel(attr, item, &Build);
+    into_token_stream(result)
+}
+
+#[proc_macro_attribute]
+pub fn map(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let attr = parse_macro_input!(attr as RangeAttributes);
+    let item = parse_macro_input!(item as MapFn);
+    let result = emit_map_kernel(attr, item, &Build);
+    into_token_stream(result)
+}
+
+#[proc_macro_attribute]
+pub fn reduce(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let attr = parse_macro_input!(attr as RangeAttributes);
+    let item = parse_macro_input!(item as ReduceFn);
+    let result = emit_reduce_kernel(attr, item, &Build);
+    into_token_stream(result)
+}
+
+#[proc_macro_attribute]
+pub fn scan(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let attr = parse_macro_input!(attr as RangeAttributes);
+    let item = parse_macro_input!(item as ReduceFn);
+    let result = emit_scan_kernel(attr, item, &Build);
+    into_token_stream(result)
+}
+
+#[proc_macro_attribute]
+pub fn device(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let attr = parse_macro_input!(attr as RangeAttributes);
+    let item = parse_macro_input!(item as DeviceItem);
+    let result = emit_device_item(attr, item, &Build);
     into_token_stream(result)
 }
 
@@ -28,18 +71,78 @@ fn into_token_stream(result: TokenResult) -> proc_macro::TokenStream {
     }
 }
 
-#[derive(Clone)]
-struct RangeAttributes;
+#[derive(Clone, Default)]
+struct RangeAttributes {
+    /// Each key set, as written.
+    keys: Vec<syn::Ident>,
+    block_dim: Option<u32>,
+    grid_dim: Option<u32>,
+    ptx: Option<PtxSource>,
+    home: Option<String>,
+    toolchain: Option<String>,
+    uses: Option<Vec<syn::Ident>>,
+    identity: Option<syn::Expr>,
+    reduce: Option<(ReduceOp, syn::Ident)>,
+    exclusive: Option<syn::LitBool>,
+    layout: Option<(Layout, syn::LitStr)>,
+}
+
+/// Where the generated launcher loads its PTX from.
+#[derive(Clone, Copy, Default)]
+enum PtxSource {
+    /// Inlined into the host binary as a string literal.
+    #[default]
+    Embed,
+    /// Read at runtime from the kernel crate's build output.
+    File,
+}
+
+/// How a range kernel returns tuples or arrays.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum Layout {
+    /// As a `Vec` of them.
+    #[default]
+    Aos,
+    /// As a tuple (or array) of a `Vec` per component.
+    Soa,
+}
+
+/// A built-in operator a range kernel can fold its values with.
+#[derive(Clone, Copy)]
+enum ReduceOp {
+    Add,
+    Mul,
+    Min,
+    Max,
+}
 
 #[derive(Clone)]
 struct RangeFn(syn::ItemFn);
 
+/// An item kernels can use: a `#[repr(C)]` struct of primitives, which they
+/// can also return, or a helper fn, const or static.
+#[derive(Clone)]
+struct DeviceItem(syn::Item);
+
+/// A map function, and whether each of its inputs is `#[broadcast]`.
+#[derive(Clone)]
+struct MapFn(syn::ItemFn, Vec<bool>);
+
+/// An associative `fn f(a: T, b: T) -> T` that reduces a buffer to one value,
+/// or scans it into its prefixes.
+#[derive(Clone)]
+struct ReduceFn(syn::ItemFn);
+
+/// The attributes of every kernel, which set how it is launched, where its
+/// crate is generated and what it is built from.
+static KERNEL_ATTRIBUTES: &[&str] = &["block_dim", "threads_per_block", "grid_dim", "ptx", "home", "toolchain", "uses"];
+
 static RANGE_FILES: &[(&str, &str, &str)] = &[
     ("Cargo.toml", "", range::CARGO_TOML),
     ("rust-toolchain.toml", "", range::RUST_TOOLCHAIN_TOML),
     ("config.toml", ".cargo", range::CONFIG_TOML),
     ("device.rs", "src", ""),
-    ("lib.rs", "src", range::LIB_RS),
+    ("lib.rs", "src", ""),
     ("kernel.ptx", "target/nvptx64-nvidia-cuda/release", ""),
 ];
 
@@ -49,51 +152,138 @@ impl ToTokens for RangeFn {
     }
 }
 
+impl ToTokens for MapFn {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.0.to_tokens(tokens);
+    }
+}
+
+impl ToTokens for ReduceFn {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.0.to_tokens(tokens);
+    }
+}
+
+/// What a kernel crate is generated from: its kernel function (renamed to
+/// `device`), the `#[spindle::device]` items that uses and its `lib.rs`.
+struct KernelCrate {
+    name: String,
+    home: PathBuf,
+    toolchain: String,
+    device: syn::ItemFn,
+    items: TokenStream,
+    lib_rs: String,
+}
+
+impl KernelCrate {
+    fn new(home: PathBuf, toolchain: String, attr: &RangeAttributes, item: &impl KernelFn, lib_rs: String) -> Result<Self, TokenStream> {
+        let device = item.device();
+        let items = device::collect(&home, attr.uses.as_deref().unwrap_or_default())?;
+        Ok(Self { name: item.name(), home, toolchain, device, items, lib_rs })
+    }
+
+    /// The tokens written to `device.rs`.
+    fn source_map(&self) -> SourceMap {
+        let Self { device, items, .. } = self;
+        SourceMap::new(quote::quote! { #items #device })
+    }
+
+    /// The path and contents of each file in the crate, given the text of its
+    /// `device.rs`. Build outputs, which start out empty, are skipped.
+    fn files<'a>(&'a self, device_rs: &'a str) -> impl Iterator<Item = (PathBuf, Cow<'a, str>)> {
+        RANGE_FILES.iter()
+            .map(move |(name, dir, contents)| {
+                let contents = match *name {
+                    "lib.rs" => Cow::Borrowed(self.lib_rs.as_str()),
+                    "device.rs" => Cow::Borrowed(device_rs),
+                    "rust-toolchain.toml" => Cow::Owned(contents.replace("{toolchain}", &self.toolchain)),
+                    _ => Cow::Borrowed(*contents),
+                };
+                (PathBuf::from(dir).join(name), contents)
+            })
+            .filter(|(_, contents)| !contents.is_empty())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct RangeSpindle {
     home: String,
     name: String,
+    toolchain: Option<String>,
     populated: bool,
     compiled: bool,
     device: Option<String>,
+    hash: Option<String>,
     msg: Option<String>,
     kernel: Option<String>,
+    #[serde(skip)]
+    signature: TokenStream,
+    #[serde(skip)]
+    source_map: SourceMap,
 }
 
 impl RangeSpindle {
-    fn generate(name: &str, device: &RangeFn) -> Result<Self, TokenStream> {
-        let spindle = PathBuf::from(KERNELS).join(name).with_extension("json");
-        let new_device = device.clone().into_token_stream().to_string();
-        let spindle = if spindle.exists() {
-            let spindle = std::fs::read_to_string(spindle).map_err(NaivelyTokenize::naively_tokenize)?;
-            let mut spindle: RangeSpindle = serde_json::from_str(&spindle).map_err(NaivelyTokenize::naively_tokenize)?;
-            spindle.update_device(new_device)?;
+    fn generate(krate: &KernelCrate) -> Result<Self, TokenStream> {
+        let KernelCrate { name, home, toolchain, device, items, lib_rs } = krate;
+        let signature = device.sig.to_token_stream();
+        let device = quote::quote! { #items #device };
+        let source_map = krate.source_map();
+        let version = cache::toolchain_version(toolchain);
+        let hash = cache::kernel_hash(
+            RANGE_FILES.iter().map(|(_, _, contents)| *contents)
+            .chain([
+                source_map.text(),
+                lib_rs.as_str(),
+                toolchain.as_str(),
+                version.as_str(),
+                env!("CARGO_PKG_VERSION"),
+            ])
+        );
+        let spindle = home.join(name).with_extension("json");
+        let new_device = device.to_string();
+        let mut spindle = if spindle.exists() {
+            let json = std::fs::read_to_string(&spindle)
+                .or_compile_error(&signature, format_args!("failed to read `{}`", spindle.display()))?;
+            let mut spindle: RangeSpindle = serde_json::from_str(&json)
+                .or_compile_error(&signature, format_args!("failed to parse `{}`", spindle.display()))?;
+            // the workspace may have moved since the manifest was written
+            spindle.home = home.display().to_string();
+            spindle.toolchain = Some(toolchain.clone());
+            spindle.signature = signature;
+            spindle.source_map = source_map;
+            if std::env::var_os(REBUILD).is_some_and(|rebuild| rebuild.ne("0")) {
+                spindle.hash = None;
+            }
+            spindle.update_device(new_device, hash)?;
             spindle
         } else {
             Self {
-                home: String::from(KERNELS),
-                name: name.into(),
+                home: home.display().to_string(),
+                name: name.clone(),
+                toolchain: Some(toolchain.clone()),
                 populated: false,
                 compiled: false,
                 device: Some(new_device),
+                hash: Some(hash),
                 msg: None,
                 kernel: None,
+                signature,
+                source_map,
             }
         };
-        let path = PathBuf::from(KERNELS).join(name);
-        std::fs::create_dir_all(&path).map_err(NaivelyTokenize::naively_tokenize)?;
-        std::fs::create_dir_all(path.join(".cargo")).map_err(NaivelyTokenize::naively_tokenize)?;
-        std::fs::create_dir_all(path.join("src")).map_err(NaivelyTokenize::naively_tokenize)?;
-        if !spindle.populated {
-            for (name, dir, contents) in RANGE_FILES {
-                if contents.ne(&"") {
-                    std::fs::write(path.join(dir).join(name), contents)
-                        .map_err(NaivelyTokenize::naively_tokenize)?;
-                }
+        let path = home.join(name);
+        for dir in [path.clone(), path.join(".cargo"), path.join("src")] {
+            std::fs::create_dir_all(&dir)
+                .or_compile_error(&spindle.signature, format_args!("failed to create `{}`", dir.display()))?;
+        }
+        if !spindle.populated || !spindle.is_cached() {
+            for (file, contents) in krate.files(spindle.source_map.text()) {
+                let file = path.join(file);
+                std::fs::write(&file, contents.as_bytes())
+                    .or_compile_error(&spindle.signature, format_args!("failed to write `{}`", file.display()))?;
             }
-            let device = device.into_token_stream().to_string();
-            std::fs::write(path.join("src/device.rs"), &device)
-                .map_err(NaivelyTokenize::naively_tokenize)?;    
+            spindle.populated = true;
+            spindle.write()?;
         }
         
         Ok(spindle)
@@ -104,93 +294,128 @@ impl RangeSpindle {
         for (file, dir, _) in RANGE_FILES {
             let path = path.join(dir).join(file);
             if path.exists() {
-                std::fs::remove_file(path).map_err(NaivelyTokenize::naively_tokenize)?;
+                std::fs::remove_file(&path)
+                    .or_compile_error(&self.signature, format_args!("failed to remove `{}`", path.display()))?;
             }
         }
         let Self {
             home: _home,
             name: _name,
+            toolchain: _,
             populated,
             compiled,
             device,
+            hash,
             msg,
-            kernel
+            kernel,
+            signature: _,
+            source_map: _,
         } = self;
         
         *populated = false;
         *compiled = false;
         *device = None;
+        *hash = None;
         *msg = None;
         *kernel = None;
         self.write()?;
         Ok(())
     }
 
-    fn update_device(&mut self, new_device: String) -> Result<(), TokenStream> {
+    /// Resets the kernel crate unless `new_hash` matches the hash of the
+    /// inputs its PTX was built from.
+    fn update_device(&mut self, new_device: String, new_hash: String) -> Result<(), TokenStream> {
+        if self.hash.as_ref().is_some_and(|hash| new_hash.eq(hash)) {
+            return Ok(())
+        }
+        
+        self.remove_files()?;
         let Self {
             home: _,
             name: _,
-            populated,
-            compiled,
+            toolchain: _,
+            populated: _,
+            compiled: _,
             device,
-            msg,
-            kernel
+            hash,
+            msg: _,
+            kernel: _,
+            signature: _,
+            source_map: _,
         } = self;
-
-        if device.as_ref().is_some_and(|device| new_device.eq(device)) {
-            return Ok(())
-        }
-        
-        *compiled = false;
-        *populated = false;
         *device = Some(new_device);
-        *msg = None;
-        *kernel = None;
-        self.remove_files()?;
+        *hash = Some(new_hash);
         self.write()
     }
 
     fn write(&self) -> Result<(), TokenStream> {
-        let json = serde_json::to_string_pretty(&self).map_err(NaivelyTokenize::naively_tokenize)?;
         let crate_json = PathBuf::from(&self.home).join(&self.name).with_extension("json");
-        std::fs::write(crate_json, json).map_err(NaivelyTokenize::naively_tokenize)
+        let json = serde_json::to_string_pretty(&self)
+            .or_compile_error(&self.signature, format_args!("failed to serialize `{}`", crate_json.display()))?;
+        std::fs::write(&crate_json, json)
+            .or_compile_error(&self.signature, format_args!("failed to write `{}`", crate_json.display()))
+    }
+
+    fn ptx_path(&self) -> PathBuf {
+        PathBuf::from(&self.home)
+            .join(&self.name)
+            .join("target")
+            .join("nvptx64-nvidia-cuda")
+            .join("release")
+            .join("kernel.ptx")
+    }
+
+    /// Whether the manifest holds PTX built from the current inputs, which
+    /// `generate` has already checked against the manifest's hash.
+    fn is_cached(&self) -> bool {
+        self.compiled
+            && self.kernel.as_ref().is_some_and(|kernel| !kernel.is_empty())
+            && self.ptx_path().exists()
     }
 
     fn compile(&mut self) -> Result<String, TokenStream> {
+        let toolchain = self.toolchain.clone().unwrap_or_else(|| toolchain::PINNED.to_string());
+        toolchain::preflight(&toolchain)
+            .map_err(|msg| compile_error(&self.signature, format_args!("cannot build the kernel crate: {msg}")))?;
         let mut cmd = std::process::Command::new("cargo");
-        let home = format!("{}/{}", self.home, self.name);
-        cmd.args([
-            "+nightly",
+        let home = PathBuf::from(&self.home).join(&self.name);
+        cmd.arg(format!("+{toolchain}"))
+        .args([
             "-Z",
             "unstable-options",
             "-C",
-            &home,
+        ])
+        .arg(&home)
+        .args([
             "build",
             "--release",
+            "--message-format=json",
         ]);
-        let output = cmd.output().map_err(NaivelyTokenize::naively_tokenize)?;
+        let mut output = cmd.output()
+            .or_compile_error(&self.signature, format_args!("failed to run `cargo +{toolchain} build` for the kernel crate"))?;
+        let diagnostics = diagnostics(&output.stdout);
+        output.stdout = rendered(&diagnostics).into_bytes();
         match command_output_result(output) {
             Ok(output) => {
+                let _kernel = self.ptx_path();
                 let Self {
-                    home,
-                    name,
+                    home: _,
+                    name: _,
+                    toolchain: _,
                     populated: _,
                     compiled,
                     device: _,
+                    hash: _,
                     msg,
-                    kernel
+                    kernel,
+                    signature,
+                    source_map: _,
                 } = self;
                 *compiled = true;
                 *msg = Some(output.clone());
-                let _kernel = PathBuf::from(&home)
-                    .join(name)
-                    .join("target")
-                    .join("nvptx64-nvidia-cuda")
-                    .join("release")
-                    .join("kernel.ptx");
                 *kernel = Some(
                     std::fs::read_to_string(&_kernel)
-                    .map_err(NaivelyTokenize::naively_tokenize)?
+                    .or_compile_error(signature, format_args!("failed to read `{}`", _kernel.display()))?
                 );
                 self.write()?;
                 Ok(output)
@@ -199,26 +424,110 @@ impl RangeSpindle {
                 let Self {
                     home: _,
                     name: _,
+                    toolchain: _,
                     populated: _,
                     compiled,
                     device: _,
+                    hash: _,
                     msg,
-                    kernel
+                    kernel,
+                    signature: _,
+                    source_map: _,
                 } = self;
                 *compiled = false;
                 *msg = Some(err.to_string());
                 *kernel = None;
                 self.write()?;
-                return Err(err.naively_tokenize());
+                Err(diagnostics_error(&diagnostics, &self.source_map, &self.signature).unwrap_or_else(|| compile_error(
+                    &self.signature,
+                    format_args!("kernel `{}` failed to compile:\n{}", self.name, strip_ansi(err.trim_end())),
+                )))
             }
         }
     }
 }
 
-static KERNELS: &'static str = "target/kernels/";
+static KERNELS: &str = "target/kernels/";
+/// Overrides where kernel crates are generated, like `#[basic_range(home = "...")]`.
+static HOME: &str = "SPINDLE_HOME";
+/// Set (to anything but `0`) to build launchers that only run on the host,
+/// skipping the kernel crate and any reference to cudarc.
+static CPU: &str = "SPINDLE_CPU";
+/// Set (to anything but `0`) to rebuild kernels even if their cached PTX is
+/// current. Proc macros only rerun when the calling crate recompiles, so this
+/// takes effect on the next change or `touch` of that crate.
+static REBUILD: &str = "SPINDLE_REBUILD";
+/// Overrides the nightly kernel crates are built with, like
+/// `#[basic_range(toolchain = "...")]`.
+static TOOLCHAIN: &str = "SPINDLE_TOOLCHAIN";
+/// Set (to anything but `0`) to generate kernel crates without building them,
+/// e.g. to type-check host code without the nightly nvptx toolchain. Kernels
+/// without cached PTX then load it from where the build would write it, so
+/// their launchers fail with `Error::KernelNotFound` until the crate is built.
+static SKIP_BUILD: &str = "SPINDLE_SKIP_BUILD";
 // static RANGE_KERNEL: &'static str = include_str!("range/src/lib.rs");
 // static RANGE_CARGO_TOML: &'static str = include_str!("range/Cargo.toml");
 
+/// Whether `SPINDLE_CPU` is set.
+fn host_only() -> bool {
+    std::env::var_os(CPU).is_some_and(|cpu| cpu.ne("0"))
+}
+
+/// Whether `SPINDLE_SKIP_BUILD` is set.
+fn skip_build() -> bool {
+    std::env::var_os(SKIP_BUILD).is_some_and(|skip| skip.ne("0"))
+}
+
+/// Resolves the directory kernel crates are generated in, from the first of
+/// the `home` attribute, `SPINDLE_HOME`, `$CARGO_TARGET_DIR/kernels` and
+/// `target/kernels/`. Relative paths are taken from the crate root (or, for
+/// `CARGO_TARGET_DIR`, from the directory cargo runs in, as cargo does) so
+/// that the result does not depend on where rustc is invoked. Each package
+/// gets its own subdirectory, since cargo builds the packages sharing a home
+/// in parallel and their kernels may have the same names.
+fn kernels_home(home: Option<&str>) -> PathBuf {
+    let manifest_dir = std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    let current_dir = std::env::current_dir().ok();
+    let (home, base) = match (home, std::env::var_os(HOME), std::env::var_os("CARGO_TARGET_DIR")) {
+        (Some(home), _, _) => (PathBuf::from(home), manifest_dir),
+        (None, Some(home), _) => (PathBuf::from(home), manifest_dir),
+        (None, None, Some(target)) => (PathBuf::from(target).join("kernels"), current_dir),
+        (None, None, None) => (PathBuf::from(KERNELS), manifest_dir),
+    };
+    let home = match base {
+        Some(base) if home.is_relative() => base.join(home),
+        _ => home,
+    };
+    match std::env::var_os("CARGO_PKG_NAME") {
+        Some(package) => home.join(package),
+        None => home,
+    }
+}
+
+/// Resolves the toolchain kernel crates are built with, from the first of the
+/// `toolchain` attribute, `SPINDLE_TOOLCHAIN`, `[package.metadata.spindle]` in
+/// the crate's `Cargo.toml` and the pinned nightly.
+fn kernels_toolchain(toolchain: Option<&str>) -> Result<String, String> {
+    if let Some(toolchain) = toolchain {
+        return Ok(toolchain.to_string())
+    }
+    let (toolchain, source) = match std::env::var(TOOLCHAIN) {
+        Ok(toolchain) => (toolchain, format!("`{TOOLCHAIN}`")),
+        Err(_) => {
+            let manifest = std::env::var_os("CARGO_MANIFEST_DIR")
+                .map(|dir| PathBuf::from(dir).join("Cargo.toml"));
+            match manifest.map(|manifest| toolchain::from_metadata(&manifest)).transpose()?.flatten() {
+                Some(toolchain) => (toolchain, "`package.metadata.spindle.toolchain`".to_string()),
+                None => return Ok(toolchain::PINNED.to_string()),
+            }
+        },
+    };
+    match toolchain::is_nightly(&toolchain) {
+        true => Ok(toolchain),
+        false => Err(format!("{source} is `{toolchain}`, but kernels need a nightly, such as `{}`", toolchain::PINNED)),
+    }
+}
+
 fn camel_word(s: &str) -> String {
     let mut chars = s.chars();
     let mut camel = if let Some(c) = chars.next() {
@@ -241,122 +550,1120 @@ fn snake_to_camel(s: &str) -> String {
 }
 
 
-impl RangeFn {
+/// The primitive components of a tuple or array return type.
+struct Components {
+    types: Vec<syn::Type>,
+    array: bool,
+}
+
+impl Components {
+    fn of(ty: &syn::Type) -> Option<Self> {
+        match ty {
+            syn::Type::Tuple(tuple) => Some(Self {
+                types: tuple.elems.iter().cloned().collect(),
+                array: false,
+            }),
+            syn::Type::Array(array) => Some(Self {
+                types: vec![*array.elem.clone(); parse::array_len(array)?],
+                array: true,
+            }),
+            _ => None,
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.types.iter().map(|ty| ty.to_token_stream().to_string()).collect()
+    }
+
+    /// The `k`th component of `value`.
+    fn get(&self, value: &TokenStream, k: usize) -> TokenStream {
+        match self.array {
+            true => quote::quote! { #value[#k] },
+            false => {
+                let k = syn::Index::from(k);
+                quote::quote! { #value.#k }
+            },
+        }
+    }
+
+    /// The tuple or array type of `wrap` applied to each component's type.
+    fn map_types(&self, wrap: impl Fn(&syn::Type) -> TokenStream) -> TokenStream {
+        match self.array {
+            true => {
+                let ty = wrap(&self.types[0]);
+                let len = self.types.len();
+                quote::quote! { [#ty; #len] }
+            },
+            false => {
+                let types = self.types.iter().map(wrap);
+                quote::quote! { ( #( #types, )* ) }
+            },
+        }
+    }
+
+    /// A tuple or array of `parts`, one per component.
+    fn collect(&self, parts: &[TokenStream]) -> TokenStream {
+        match self.array {
+            true => quote::quote! { [ #( #parts ),* ] },
+            false => quote::quote! { ( #( #parts, )* ) },
+        }
+    }
+}
+
+/// The function behind a kernel, which is copied into the kernel crate as
+/// `device::device` and kept on the host for the fallback path.
+trait KernelFn {
+    fn item(&self) -> &syn::ItemFn;
+
     fn name(&self) -> String {
-        self.0.sig.ident.to_string()
+        self.item().sig.ident.to_string()
+    }
+
+    fn ident(&self) -> &syn::Ident {
+        &self.item().sig.ident
+    }
+
+    fn trait_name(&self) -> syn::Ident {
+        syn::Ident::new(&format!("_{}", snake_to_camel(&self.name())), self.ident().span())
+    }
+
+    fn device(&self) -> syn::ItemFn {
+        let mut device = self.item().clone();
+        device.vis = syn::Visibility::Public(Default::default());
+        device.sig.ident = syn::Ident::new("device", device.sig.ident.span());
+        device
+    }
+
+    fn input_type(&self) -> &syn::Type {
+        match self.item().sig.inputs.first() {
+            Some(syn::FnArg::Typed(p)) => &p.ty,
+            _ => unreachable!("kernel functions have typed inputs"),
+        }
     }
 
-    fn rename(&mut self, name: &str) {
-        self.0.sig.ident = syn::Ident::new(name, self.0.sig.ident.span());
+    fn return_type(&self) -> &syn::Type {
+        match &self.item().sig.output {
+            syn::ReturnType::Default => unreachable!("kernel functions have a return type"),
+            syn::ReturnType::Type(_, return_type) => return_type,
+        }
     }
+}
 
-    fn make_visible(&mut self) {
-        self.0.vis = syn::Visibility::Public(Default::default());
+impl KernelFn for RangeFn {
+    fn item(&self) -> &syn::ItemFn {
+        &self.0
+    }
+}
+
+impl KernelFn for MapFn {
+    fn item(&self) -> &syn::ItemFn {
+        &self.0
+    }
+}
+
+impl KernelFn for ReduceFn {
+    fn item(&self) -> &syn::ItemFn {
+        &self.0
+    }
+}
+
+impl MapFn {
+    /// The element type of `fn f(x: &mut T)`, which maps a buffer in place.
+    fn in_place_type(&self) -> Option<&syn::Type> {
+        match self.input_type() {
+            syn::Type::Reference(reference) => Some(&reference.elem),
+            _ => None,
+        }
+    }
+}
+
+impl ReduceOp {
+    /// The value `combine` leaves the other operand unchanged with.
+    fn identity(self, item_type: &syn::Type) -> TokenStream {
+        let float = matches!(item_type.to_token_stream().to_string().as_str(), "f32" | "f64");
+        match (self, float) {
+            (Self::Add, _) => quote::quote! { 0 as #item_type },
+            (Self::Mul, _) => quote::quote! { 1 as #item_type },
+            (Self::Min, false) => quote::quote! { #item_type::MAX },
+            (Self::Min, true) => quote::quote! { #item_type::INFINITY },
+            (Self::Max, false) => quote::quote! { #item_type::MIN },
+            (Self::Max, true) => quote::quote! { #item_type::NEG_INFINITY },
+        }
+    }
+
+    /// The operator as an expression in `a` and `b`, for both the kernel and the host.
+    fn combine(self) -> TokenStream {
+        match self {
+            Self::Add => quote::quote! { a + b },
+            Self::Mul => quote::quote! { a * b },
+            Self::Min => quote::quote! { a.min(b) },
+            Self::Max => quote::quote! { a.max(b) },
+        }
+    }
+}
+
+impl RangeAttributes {
+    /// Rejects the attributes set here that `#[kind]` doesn't `use`.
+    fn reject_unused(&self, kind: &str, used: &[&str]) -> Result<(), TokenStream> {
+        match self.keys.iter().find(|key| !used.contains(&key.to_string().as_str())) {
+            Some(key) => Err(compile_error(&key.to_token_stream(), format!("`{key}` is not used by `#[spindle::{kind}]`"))),
+            None => Ok(()),
+        }
+    }
+
+    fn launch_config(&self, n: &TokenStream) -> TokenStream {
+        // the kernel strides over the grid, so a capped or fixed grid still covers
+        // every index; CUDA caps `gridDim.x` at 2^31 - 1
+        let grid_dim = |block_dim: u32| match self.grid_dim {
+            Some(grid_dim) => quote::quote! { #grid_dim },
+            None => quote::quote! {
+                #n.div_ceil(#block_dim as usize).clamp(1, i32::MAX as usize) as u32
+            },
+        };
+        // not `LaunchConfig::for_num_elems`, which overflows a `u32` near `u32::MAX` elements
+        let block_dim = self.block_dim.unwrap_or(1024);
+        let grid_dim = grid_dim(block_dim);
+        quote::quote! {
+            LaunchConfig {
+                grid_dim: (#grid_dim, 1, 1),
+                block_dim: (#block_dim, 1, 1),
+                shared_mem_bytes: 0,
+            }
+        }
+    }
+}
+
+/// Where emitters get each kernel's PTX from, so that the host code they emit
+/// does not depend on building the kernel crate.
+trait Kernels {
+    /// The expression `item`'s launcher loads its PTX with, or `None` for a
+    /// launcher that only runs on the host.
+    fn ptx(&self, attr: &RangeAttributes, item: &impl KernelFn, lib_rs: String) -> Result<Option<TokenStream>, TokenStream>;
+
+    /// Where `#[spindle::device]` items are saved and kernel crates are
+    /// generated, or `None` when kernels only run on the host.
+    fn home(&self, attr: &RangeAttributes) -> Option<PathBuf>;
+}
+
+/// Generates and builds (or reuses) each kernel crate, or skips it under
+/// `SPINDLE_CPU`.
+struct Build;
+
+impl Kernels for Build {
+    fn ptx(&self, attr: &RangeAttributes, item: &impl KernelFn, lib_rs: String) -> Result<Option<TokenStream>, TokenStream> {
+        let Some(home) = self.home(attr) else {
+            return Ok(None)
+        };
+        let toolchain = kernels_toolchain(attr.toolchain.as_deref())
+            .map_err(|msg| compile_error(&item.device().sig.to_token_stream(), msg))?;
+        let krate = KernelCrate::new(home, toolchain, attr, item, lib_rs)?;
+        let name = &krate.name;
+        let mut spindle = RangeSpindle::generate(&krate)?;
+        const WARNING: &str = "\
+            #![no_std] \
+            #![feature(abi_ptx)] \
+            #![feature(stdsimd)] \
+            #![feature(core_intrinsics)] \
+            core::arch::nvptx::*; \
+        ";
+        const COLOR: &str = "\x1b[33m";
+        const RESET: &str = "\x1b[0m";
+        println!("{COLOR}{name} uses {}{}", WARNING, RESET);
+        let span = item.ident().span();
+        if !spindle.is_cached() {
+            if skip_build() {
+                let ptx_path = spindle.ptx_path().display().to_string();
+                println!("{COLOR}{name} is not built, since {SKIP_BUILD} is set; its launcher needs `{ptx_path}`{RESET}");
+                return Ok(Some(ptx_file(&spindle.ptx_path(), span)))
+            }
+            let output = spindle.compile()?;
+            println!("{}", output.trim_end());
+        }
+        Ok(Some(match attr.ptx.unwrap_or_default() {
+            PtxSource::Embed => {
+                let ptx_src = syn::LitStr::new(spindle.kernel.as_deref().unwrap_or_default(), span);
+                quote::quote! { Ptx::from_src(#ptx_src) }
+            },
+            PtxSource::File => ptx_file(&spindle.ptx_path(), span),
+        }))
+    }
+
+    fn home(&self, attr: &RangeAttributes) -> Option<PathBuf> {
+        (!host_only()).then(|| kernels_home(attr.home.as_deref()))
+    }
+}
+
+/// Loads the PTX at `path`, whose launcher fails with `KernelNotFound` rather
+/// than a driver error if the kernel crate has not been built there.
+fn ptx_file(path: &std::path::Path, span: proc_macro2::Span) -> TokenStream {
+    let path = syn::LitStr::new(&path.display().to_string(), span);
+    quote::quote! {{
+        if !std::path::Path::new(#path).exists() {
+            return Err(Error::KernelNotFound)
+        }
+        Ptx::from_file(#path)
+    }}
+}
+
+/// An expression for a launcher's output: `launch` run with the kernel `f`
+/// loaded on `dev`, or `on_host` without PTX or a CUDA device.
+fn launch_or_fallback(ptx: &Option<TokenStream>, launch: TokenStream, on_host: TokenStream) -> TokenStream {
+    let Some(ptx) = ptx else {
+        return on_host
+    };
+    quote::quote! {
+        match cudarc::driver::CudaDevice::new(0) {
+            // without a CUDA device, fall back to the host
+            Err(_) => #on_host,
+            Ok(dev) => {
+                use spindle::range::Error;
+                use cudarc::{driver::{LaunchAsync, LaunchConfig}, nvrtc::Ptx};
+                dev.load_ptx(
+                    #ptx,
+                    "kernel",
+                    &["kernel"]
+                )?;
+                let f = dev.get_func("kernel", "kernel").ok_or(Error::KernelNotFound)?;
+                #launch
+            },
+        }
     }
 }
 
-fn emit_range_kernel(_attr: RangeAttributes, item: RangeFn) -> TokenResult {
-    let name = item.name();
+/// Runs `serial` on the host, or `parallel` (with rayon's prelude in scope) if
+/// the calling crate has a `rayon` feature, e.g. an optional `rayon` dependency.
+/// Launchers containing it allow `unexpected_cfgs` for crates without one.
+fn on_host(serial: TokenStream, parallel: TokenStream) -> TokenStream {
+    quote::quote! {{
+        #[cfg(feature = "rayon")]
+        let out_host = {
+            use rayon::prelude::*;
+            #parallel
+        };
+        #[cfg(not(feature = "rayon"))]
+        let out_host = #serial;
+        out_host
+    }}
+}
+
+fn emit_device_item(attr: RangeAttributes, item: DeviceItem, kernels: &impl Kernels) -> TokenResult {
+    attr.reject_unused("device", &["home", "uses"])?;
+    let DeviceItem(item) = item;
     let mut device = item.clone();
-    device.make_visible();
-    device.rename("device");
-    let mut spindle = RangeSpindle::generate(&name, &device)?;
-    const WARNING: &'static str = "\
-        #![no_std] \
-        #![feature(abi_ptx)] \
-        #![feature(stdsimd)] \
-        #![feature(core_intrinsics)] \
-        core::arch::nvptx::*; \
-    ";
-    const COLOR: &'static str = "\x1b[33m";
-    const RESET: &'static str = "\x1b[0m";
-    println!("{COLOR}{name} uses {}{}", WARNING, RESET);
-    let output = spindle.compile()?;
-    println!("{}", output.trim_end());
-
-    let name = &item.0.sig.ident;
-    let launch_name = syn::Ident::new(
-        &format!("_{name}"),
-        item.0.sig.ident.span()
-    );
+    let name = match &mut device {
+        syn::Item::Struct(device) => {
+            // the kernel crate's copy is read from `lib.rs`, outside `device.rs`
+            device.vis = syn::Visibility::Public(Default::default());
+            device.ident.clone()
+        },
+        syn::Item::Fn(device) => device.sig.ident.clone(),
+        syn::Item::Const(device) => device.ident.clone(),
+        syn::Item::Static(device) => device.ident.clone(),
+        _ => unreachable!("device items are structs, fns, consts or statics"),
+    };
+    let home = kernels.home(&attr);
+    if let Some(home) = &home {
+        device::save(home, &name.to_string(), &device.to_token_stream(), attr.uses.as_deref().unwrap_or_default())?;
+    }
+    // items are saved by name, so a second item of the same name anywhere in
+    // the crate would replace the first; this makes it "defined multiple times"
+    let marker = syn::Ident::new(&format!("__spindle_device_{name}"), name.span());
+    let marker = quote::quote_spanned! {name.span()=>
+        #[doc(hidden)]
+        #[macro_export]
+        macro_rules! #marker { () => {} }
+    };
+    let syn::Item::Struct(_) = item else {
+        return Ok(quote::quote! { #item #marker })
+    };
+    let cuda = match home {
+        None => TokenStream::new(),
+        Some(_) => {
+            // SAFETY: the struct is `#[repr(C)]` and `Copy`, and its fields are
+            // primitive numbers, which are valid as zero bits
+            quote::quote! {
+                unsafe impl cudarc::driver::DeviceRepr for #name {}
+                unsafe impl cudarc::driver::ValidAsZeroBits for #name {}
+            }
+        },
+    };
+    Ok(quote::quote! {
+        #item
+        #marker
+        const _: fn() = || {
+            fn device_structs_are_copy<T: Copy>() {}
+            device_structs_are_copy::<#name>();
+        };
+        #cuda
+    })
+}
 
-    let input_type = match item.0.sig.inputs.first().unwrap() {
-        syn::FnArg::Receiver(_) => todo!("Range fn is not a method"),
-        syn::FnArg::Typed(p) => &p.ty,
+fn emit_range_kernel(mut attr: RangeAttributes, item: RangeFn, kernels: &impl Kernels) -> TokenResult {
+    attr.reject_unused("basic_range", &[KERNEL_ATTRIBUTES, &["reduce", "layout"]].concat())?;
+    let input_type = item.input_type();
+    let return_type = item.return_type();
+    let return_name = return_type.to_token_stream().to_string();
+    let returns_struct = !parse::PRIMITIVES.contains(&return_name.as_str());
+    if let Some((op, op_name)) = &attr.reduce {
+        if returns_struct {
+            return Err(compile_error(&op_name.to_token_stream(), "`reduce` needs a primitive number return type"))
+        }
+        return emit_range_reduce_kernel(&attr, &item, *op, kernels)
+    }
+    if let Some(components) = Components::of(return_type) {
+        return emit_range_components_kernel(&attr, &item, &components, kernels)
+    }
+    if let Some((_, layout)) = &attr.layout {
+        return Err(compile_error(&layout.to_token_stream(), "`layout` is only used by tuple and array returns"))
+    }
+    // a struct return is copied into the kernel crate, where it lives in `device.rs`
+    let (return_name, layout_check) = match kernels.home(&attr).filter(|_| returns_struct) {
+        Some(home) => {
+            let saved = device::saved_struct(&home, &return_name, &return_type.to_token_stream())?;
+            if let syn::Type::Path(path) = return_type {
+                attr.uses.get_or_insert_with(Vec::new).extend(path.path.get_ident().cloned());
+            }
+            let layout_check = layout_check(return_type, saved);
+            (format!("device::{return_name}"), layout_check)
+        },
+        None => (return_name, TokenStream::new()),
     };
+    let lib_rs = range::lib_rs(
+        &input_type.to_token_stream().to_string(),
+        &return_name,
+    );
+    let ptx = kernels.ptx(&attr, &item, lib_rs)?;
 
-    let return_type = match &device.0.sig.output {
-        syn::ReturnType::Default => unreachable!("RangeFn has a return type"),
-        syn::ReturnType::Type(_, return_type) => return_type,
+    let name = item.ident();
+    let launch_kernel = |n: TokenStream| {
+        let config = attr.launch_config(&n);
+        let launch = quote::quote! {
+            let mut out_dev = dev.alloc_zeros::<#return_type>(#n)?;
+            let config = #config;
+            // SAFETY: `kernel` takes the output buffer and its length, and only
+            // writes below that length
+            unsafe { f.launch(config, (&mut out_dev, #n as #input_type)) }?;
+            dev.dtoh_sync_copy(&out_dev)?
+        };
+        let on_host = on_host(
+            quote::quote! { (0..#n).map(|i| #name(i as #input_type)).collect::<Vec<#return_type>>() },
+            quote::quote! { (0..#n).into_par_iter().map(|i| #name(i as #input_type)).collect::<Vec<#return_type>>() },
+        );
+        let out_host = launch_or_fallback(&ptx, launch, on_host);
+        quote::quote! {
+            let out_host = #out_host;
+        }
     };
+    let launchers = emit_range_launchers(
+        &item,
+        quote::quote! { Vec<#return_type> },
+        quote::quote! { Box<[#return_type; N]> },
+        quote::quote! { Vec::new() },
+        quote::quote! { out_host.try_into().map_err(|_| spindle::range::Error::LengthMismatch) },
+        launch_kernel,
+    );
+    Ok(quote::quote! {
+        #launchers
+        #layout_check
+    })
+}
 
-    let trait_name = syn::Ident::new(
-        &format!("_{}", snake_to_camel(&item.name())),
-        item.0.sig.ident.span()
+/// Fails the build unless `return_type` has the layout of the `saved` copy of
+/// it the kernel crate was built with. That copy comes from whichever
+/// expansion of its `#[spindle::device]` item last ran, which may not have
+/// seen the current definition yet. Fields are only compared when they are
+/// all `pub`, since the struct may be declared in another module.
+fn layout_check(return_type: &syn::Type, saved: syn::ItemStruct) -> TokenStream {
+    let msg = format!(
+        "the kernel crate's copy of `{}` is out of date; build again to update it",
+        saved.ident,
     );
+    let public = saved.fields.iter().all(|field| matches!(field.vis, syn::Visibility::Public(_)));
+    let (members, types): (Vec<syn::Member>, Vec<syn::Type>) = match public {
+        true => saved.fields.members().zip(saved.fields.iter().map(|field| field.ty.clone())).unzip(),
+        false => Default::default(),
+    };
+    let fields = match public {
+        true => quote::quote! {
+            const _: fn(&#return_type) -> ( #( &#types, )* ) = |value| ( #( &value.#members, )* );
+        },
+        false => TokenStream::new(),
+    };
+    let saved = syn::ItemStruct {
+        attrs: saved.attrs.into_iter().filter(|attr| attr.path().is_ident("repr")).collect(),
+        vis: syn::Visibility::Inherited,
+        ident: syn::Ident::new("Saved", saved.ident.span()),
+        ..saved
+    };
+    quote::quote! {
+        const _: () = {
+            #[allow(dead_code)]
+            #saved
+            assert!(
+                core::mem::size_of::<#return_type>() == core::mem::size_of::<Saved>()
+                    && core::mem::align_of::<#return_type>() == core::mem::align_of::<Saved>()
+                    #( && core::mem::offset_of!(#return_type, #members) == core::mem::offset_of!(Saved, #members) )*,
+                #msg
+            );
+        };
+        #fields
+    }
+}
 
-    let range_trait = quote::quote! {
+/// The trait a range function's launcher is a method of, its impl on the
+/// index type and the `_name::<N>` launcher, which differ in their launch.
+/// `launch(n)` leaves the values for the indices below `n` in `out_host`, or
+/// `empty` stands in for them when there are none, which the impl returns and
+/// the launcher turns into its result with `into_launcher`.
+fn emit_range_launchers(
+    item: &RangeFn,
+    returns: TokenStream,
+    launcher_returns: TokenStream,
+    empty: TokenStream,
+    into_launcher: TokenStream,
+    launch: impl Fn(TokenStream) -> TokenStream,
+) -> TokenStream {
+    let name = item.ident();
+    let input_type = item.input_type();
+    let launch_name = quote::format_ident!("_{name}");
+    let trait_name = item.trait_name();
+    let launch_little_n = launch(quote::quote! { n });
+    let launch_big_n = launch(quote::quote! { N });
+    quote::quote! {
+        #item
         trait #trait_name {
             type Returns;
-            unsafe fn #name (&self) -> Result<Self::Returns, spindle::range::Error>;
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error>;
+        }
+        impl #trait_name for #input_type {
+            type Returns = #returns;
+            #[allow(unexpected_cfgs)]
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error> {
+                // negative ranges are empty, like `0..n`
+                let n = usize::try_from(*self).unwrap_or(0);
+                // the driver rejects empty buffers and grids
+                if n == 0 {
+                    return Ok(#empty)
+                }
+                #launch_little_n
+                Ok(out_host)
+            }
+        }
+        #[allow(unexpected_cfgs)]
+        fn #launch_name <const N: usize>() -> Result<#launcher_returns, spindle::range::Error> {
+            // the kernel and the host would wrap an `N` past the index type
+            <#input_type>::try_from(N).map_err(|_| spindle::range::Error::LengthMismatch)?;
+            let out_host = match N {
+                0 => #empty,
+                _ => {
+                    #launch_big_n
+                    out_host
+                },
+            };
+            #into_launcher
+        }
+    }
+}
+
+fn emit_range_components_kernel(attr: &RangeAttributes, item: &RangeFn, components: &Components, kernels: &impl Kernels) -> TokenResult {
+    let layout = attr.layout.as_ref().map(|(layout, _)| *layout).unwrap_or_default();
+    let input_type = item.input_type();
+    let return_type = item.return_type();
+    // `kernel` takes a buffer per component and its length, within cudarc's 12 parameters
+    if layout == Layout::Soa && components.types.len() > 11 {
+        let msg = "struct-of-arrays returns have at most 11 components";
+        return Err(compile_error(&return_type.to_token_stream(), msg))
+    }
+    let index_type = input_type.to_token_stream().to_string();
+    let lib_rs = match layout {
+        Layout::Aos => range::aos_lib_rs(&index_type, &components.names(), components.array),
+        Layout::Soa => range::soa_lib_rs(&index_type, &components.names(), components.array),
+    };
+    let ptx = kernels.ptx(attr, item, lib_rs)?;
+
+    let name = item.ident();
+    let types = &components.types;
+    let outs: Vec<syn::Ident> = (0..types.len())
+        .map(|k| quote::format_ident!("out_{k}"))
+        .collect();
+    let value = quote::quote! { value };
+    let parts: Vec<TokenStream> = (0..types.len())
+        .map(|k| components.get(&value, k))
+        .collect();
+    let (returns, launcher_returns) = match layout {
+        Layout::Aos => (
+            quote::quote! { Vec<#return_type> },
+            quote::quote! { Box<[#return_type; N]> },
+        ),
+        Layout::Soa => (
+            components.map_types(|ty| quote::quote! { Vec<#ty> }),
+            components.map_types(|ty| quote::quote! { Box<[#ty; N]> }),
+        ),
+    };
+
+    let launch_kernel = |n: TokenStream| {
+        let config = attr.launch_config(&n);
+        let launch = match layout {
+            Layout::Aos => {
+                // an array is the struct's one field, and a tuple's components are its fields
+                let (fields, unwrap) = match components.array {
+                    true => (
+                        quote::quote! { #return_type },
+                        quote::quote! { |Returns(value)| value },
+                    ),
+                    false => (
+                        quote::quote! { #( #types ),* },
+                        quote::quote! { |Returns( #( #outs ),* )| ( #( #outs, )* ) },
+                    ),
+                };
+                quote::quote! {
+                    // mirrors the kernel crate's `Returns`, since tuples have no stable layout
+                    #[repr(C)]
+                    #[derive(Clone, Copy)]
+                    struct Returns(#fields);
+                    // SAFETY: `Returns` is `#[repr(C)]` and `Copy`, and its fields are
+                    // primitive numbers, which are valid as zero bits
+                    unsafe impl cudarc::driver::DeviceRepr for Returns {}
+                    unsafe impl cudarc::driver::ValidAsZeroBits for Returns {}
+                    let mut out_dev = dev.alloc_zeros::<Returns>(#n)?;
+                    let config = #config;
+                    // SAFETY: `kernel` takes the output buffer and its length, and only
+                    // writes below that length
+                    unsafe { f.launch(config, (&mut out_dev, #n as #input_type)) }?;
+                    dev.dtoh_sync_copy(&out_dev)?
+                        .into_iter()
+                        .map(#unwrap)
+                        .collect::<Vec<#return_type>>()
+                }
+            },
+            Layout::Soa => {
+                let collected = components.collect(&outs.iter()
+                    .map(|out| quote::quote! { dev.dtoh_sync_copy(&#out)? })
+                    .collect::<Vec<_>>());
+                quote::quote! {
+                    #( let mut #outs = dev.alloc_zeros::<#types>(#n)?; )*
+                    let config = #config;
+                    // SAFETY: `kernel` takes an output buffer per component and
+                    // their common length, and only writes below that length
+                    unsafe { f.launch(config, ( #( &mut #outs, )* #n as #input_type )) }?;
+                    #collected
+                }
+            },
+        };
+        let split = |values: TokenStream| match layout {
+            Layout::Aos => values,
+            Layout::Soa => {
+                let columns: Vec<TokenStream> = parts.iter().zip(types)
+                    .map(|(part, ty)| quote::quote! { values.iter().map(|value| #part).collect::<Vec<#ty>>() })
+                    .collect();
+                let columns = components.collect(&columns);
+                quote::quote! {{
+                    let values = #values;
+                    #columns
+                }}
+            },
+        };
+        let on_host = on_host(
+            split(quote::quote! { (0..#n).map(|i| #name(i as #input_type)).collect::<Vec<#return_type>>() }),
+            split(quote::quote! { (0..#n).into_par_iter().map(|i| #name(i as #input_type)).collect::<Vec<#return_type>>() }),
+        );
+        let out_host = launch_or_fallback(&ptx, launch, on_host);
+        quote::quote! {
+            let out_host = #out_host;
         }
     };
+    let empty = match layout {
+        Layout::Aos => quote::quote! { Vec::new() },
+        Layout::Soa => components.collect(&vec![quote::quote! { Vec::new() }; types.len()]),
+    };
+    let into_arrays = match layout {
+        Layout::Aos => quote::quote! {
+            out_host.try_into().map_err(|_| spindle::range::Error::LengthMismatch)
+        },
+        Layout::Soa => {
+            let pattern = components.collect(&outs.iter().map(ToTokens::to_token_stream).collect::<Vec<_>>());
+            let arrays = components.collect(&outs.iter()
+                .map(|out| quote::quote! { #out.try_into().map_err(|_| spindle::range::Error::LengthMismatch)? })
+                .collect::<Vec<_>>());
+            quote::quote! {
+                let #pattern = out_host;
+                Ok(#arrays)
+            }
+        },
+    };
+    Ok(emit_range_launchers(item, returns, launcher_returns, empty, into_arrays, launch_kernel))
+}
 
-    let ptx_path = syn::LitStr::new(
-        &format!("target/kernels/{}/target/nvptx64-nvidia-cuda/release/kernel.ptx", name),
-        name.span()
+fn emit_range_reduce_kernel(attr: &RangeAttributes, item: &RangeFn, op: ReduceOp, kernels: &impl Kernels) -> TokenResult {
+    let input_type = item.input_type();
+    let return_type = item.return_type();
+    let combine = op.combine();
+    let lib_rs = reduce::range_lib_rs(
+        &input_type.to_token_stream().to_string(),
+        &return_type.to_token_stream().to_string(),
+        &combine.to_string(),
     );
+    let ptx = kernels.ptx(attr, item, lib_rs)?;
 
-    let little_n = quote::quote! { n };
-    let big_n = quote::quote! { N };
-    let launch_kernel = |n: TokenStream| quote::quote! {
-        let layout = core::alloc::Layout::array::<#return_type>(#n)?;
-        use spindle::range::Error;
-        use cudarc::{driver::{CudaDevice, DriverError, LaunchAsync, LaunchConfig}, nvrtc::Ptx};
-        let dev = CudaDevice::new(0)?;
-        dev.load_ptx(
-            Ptx::from_file(#ptx_path),
-            "kernel",
-            &["kernel"]
-        )?;
-        let f = dev.get_func("kernel", "kernel").ok_or(Error::KernelNotFound)?;
-        let mut out_host_ptr = std::alloc::alloc(layout.clone());
-        let out_host_vec = if out_host_ptr.is_null() {
-            std::alloc::dealloc(out_host_ptr, layout);
-            return Err(Error::AllocationFailed);
-        } else {
-            Vec::from_raw_parts(out_host_ptr as *mut #return_type, #n, #n)
+    let name = item.ident();
+    let identity = op.identity(return_type);
+
+    // one fused pass leaves a partial result per block, which the host folds
+    let block_dim = attr.block_dim.unwrap_or(256);
+    let max_grid_dim = attr.grid_dim.unwrap_or(1024);
+    let launch_kernel = |n: TokenStream| {
+        let launch = quote::quote! {
+            let block_dim: u32 = #block_dim;
+            let grid_dim = #n.div_ceil(block_dim as usize).clamp(1, #max_grid_dim as usize) as u32;
+            let mut scratch_dev = dev.alloc_zeros::<#return_type>(grid_dim as usize * block_dim as usize)?;
+            let mut out_dev = dev.alloc_zeros::<#return_type>(grid_dim as usize)?;
+            let config = LaunchConfig {
+                grid_dim: (grid_dim, 1, 1),
+                block_dim: (block_dim, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            // SAFETY: `kernel` takes a scratch buffer of `block_dim` values per
+            // block and an output buffer of one value per block, and only
+            // accesses them within those bounds
+            unsafe { f.launch(config, (&mut scratch_dev, &mut out_dev, #n as #input_type, identity)) }?;
+            dev.sync_reclaim(out_dev)?.into_iter().fold(identity, combine)
         };
-        let mut out_dev = dev.htod_copy(out_host_vec)?;
-        let config = LaunchConfig::for_num_elems(#n as u32);
-        f.launch(config, (&mut out_dev, #n as i32))?;
-        let out_host_2 = dev.sync_reclaim(out_dev)?;
-    };
-    let launch_little_n = launch_kernel(little_n);
-    let launch_big_n = launch_kernel(big_n);
-    
-    let int_impl = quote::quote! {
-        impl #trait_name for #input_type {
+        let on_host = on_host(
+            quote::quote! { (0..#n).map(|i| #name(i as #input_type)).fold(identity, combine) },
+            quote::quote! { (0..#n).into_par_iter().map(|i| #name(i as #input_type)).reduce(|| identity, combine) },
+        );
+        let out_host = launch_or_fallback(&ptx, launch, on_host);
+        quote::quote! {
+            let identity: #return_type = #identity;
+            let combine = |a: #return_type, b: #return_type| -> #return_type { #combine };
+            let out_host = #out_host;
+        }
+    };
+    Ok(emit_range_launchers(
+        item,
+        return_type.to_token_stream(),
+        return_type.to_token_stream(),
+        identity.clone(),
+        quote::quote! { Ok(out_host) },
+        launch_kernel,
+    ))
+}
+
+fn emit_map_kernel(attr: RangeAttributes, item: MapFn, kernels: &impl Kernels) -> TokenResult {
+    attr.reject_unused("map", KERNEL_ATTRIBUTES)?;
+    if let Some(input_type) = item.in_place_type() {
+        return emit_in_place_kernel(&attr, &item, input_type, kernels)
+    }
+    if item.1.len() > 1 {
+        return emit_zip_kernel(&attr, &item, kernels)
+    }
+    let input_type = item.input_type();
+    let return_type = item.return_type();
+    let lib_rs = map::lib_rs(
+        &input_type.to_token_stream().to_string(),
+        &return_type.to_token_stream().to_string(),
+    );
+    let ptx = kernels.ptx(&attr, &item, lib_rs)?;
+
+    let name = item.ident();
+    let trait_name = item.trait_name();
+
+    let map_trait = quote::quote! {
+        trait #trait_name {
+            type Returns;
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error>;
+        }
+    };
+
+    let n = quote::quote! { n };
+    let config = attr.launch_config(&n);
+    let launch = quote::quote! {
+        let in_dev = dev.htod_sync_copy(self)?;
+        let mut out_dev = dev.alloc_zeros::<#return_type>(n)?;
+        let config = #config;
+        // SAFETY: `kernel` takes the input and output buffers and their common
+        // length, and only accesses them below that length
+        unsafe { f.launch(config, (&in_dev, &mut out_dev, n)) }?;
+        dev.sync_reclaim(out_dev)?
+    };
+    let on_host = on_host(
+        quote::quote! { self.iter().map(|x| #name(*x)).collect::<Vec<#return_type>>() },
+        quote::quote! { self.par_iter().map(|x| #name(*x)).collect::<Vec<#return_type>>() },
+    );
+    let out_host = launch_or_fallback(&ptx, launch, on_host);
+
+    // `Vec<T>` and `[T; N]` reach this through auto-deref and unsizing
+    let slice_impl = quote::quote! {
+        impl #trait_name for [#input_type] {
             type Returns = Vec<#return_type>;
-            unsafe fn #name (&self) -> Result<Self::Returns, spindle::range::Error> {
-                let n = *self as usize; //todo! does `as` branch?
-                #launch_little_n
-                Ok(out_host_2)
+            #[allow(unexpected_cfgs)]
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error> {
+                let n = self.len();
+                if n == 0 {
+                    return Ok(Vec::new())
+                }
+                let out_host = #out_host;
+                Ok(out_host)
             }
         }
     };
-    let launcher = quote::quote! {
-        unsafe fn #launch_name <const N: usize>() -> Result<Box<[ #return_type ; N ]>, spindle::range::Error> {
-            #launch_big_n
-            out_host_2.try_into().map_err(|_| Error::LengthMismatch)
+    let vec_impl = quote::quote! {
+        impl #trait_name for Vec<#input_type> {
+            type Returns = Vec<#return_type>;
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error> {
+                <[#input_type] as #trait_name>::#name(self)
+            }
+        }
+    };
+    let array_impl = quote::quote! {
+        impl<const N: usize> #trait_name for [#input_type; N] {
+            type Returns = Box<[#return_type; N]>;
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error> {
+                <[#input_type] as #trait_name>::#name(self)?
+                    .try_into()
+                    .map_err(|_| spindle::range::Error::LengthMismatch)
+            }
+        }
+    };
+    Ok(quote::quote! {
+        #item
+        #map_trait
+        #slice_impl
+        #vec_impl
+        #array_impl
+    })
+}
+
+fn emit_in_place_kernel(attr: &RangeAttributes, item: &MapFn, input_type: &syn::Type, kernels: &impl Kernels) -> TokenResult {
+    let lib_rs = map::in_place_lib_rs(&input_type.to_token_stream().to_string());
+    let ptx = kernels.ptx(attr, item, lib_rs)?;
+
+    let name = item.ident();
+    let trait_name = item.trait_name();
+
+    let map_trait = quote::quote! {
+        trait #trait_name {
+            fn #name (&mut self) -> Result<(), spindle::range::Error>;
+        }
+    };
+
+    let n = quote::quote! { n };
+    let config = attr.launch_config(&n);
+    let launch = quote::quote! {
+        let mut data_dev = dev.htod_sync_copy(self)?;
+        let config = #config;
+        // SAFETY: `kernel` takes the buffer and its length, and only accesses
+        // it below that length
+        unsafe { f.launch(config, (&mut data_dev, n)) }?;
+        dev.dtoh_sync_copy_into(&data_dev, self)?
+    };
+    let on_host = on_host(
+        quote::quote! { self.iter_mut().for_each(#name) },
+        quote::quote! { self.par_iter_mut().for_each(#name) },
+    );
+    let out_host = launch_or_fallback(&ptx, launch, on_host);
+
+    let slice_impl = quote::quote! {
+        impl #trait_name for [#input_type] {
+            #[allow(unexpected_cfgs)]
+            fn #name (&mut self) -> Result<(), spindle::range::Error> {
+                let n = self.len();
+                if n == 0 {
+                    return Ok(())
+                }
+                #out_host;
+                Ok(())
+            }
+        }
+    };
+    Ok(quote::quote! {
+        #item
+        #map_trait
+        #slice_impl
+    })
+}
+
+fn emit_zip_kernel(attr: &RangeAttributes, item: &MapFn, kernels: &impl Kernels) -> TokenResult {
+    let input_types: Vec<&syn::Type> = item.0.sig.inputs.iter()
+        .map(|arg| match arg {
+            syn::FnArg::Typed(arg) => arg.ty.as_ref(),
+            syn::FnArg::Receiver(_) => unreachable!("map functions are not methods"),
+        })
+        .collect();
+    let broadcast = &item.1;
+    let return_type = item.return_type();
+    let lib_rs = map::zip_lib_rs(
+        &input_types.iter()
+            .zip(broadcast)
+            .map(|(input_type, broadcast)| (input_type.to_token_stream().to_string(), *broadcast))
+            .collect::<Vec<_>>(),
+        &return_type.to_token_stream().to_string(),
+    );
+    let ptx = kernels.ptx(attr, item, lib_rs)?;
+
+    let name = item.ident();
+    let trait_name = item.trait_name();
+
+    let map_trait = quote::quote! {
+        trait #trait_name {
+            type Returns;
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error>;
+        }
+    };
+
+    // broadcast inputs are taken by value, the others as anything viewable as a slice
+    let span = item.ident().span();
+    let inputs: Vec<syn::Ident> = (0..input_types.len())
+        .map(|k| syn::Ident::new(&format!("input_{k}"), span))
+        .collect();
+    let buffers: Vec<&syn::Ident> = inputs.iter()
+        .zip(broadcast)
+        .filter_map(|(input, broadcast)| (!broadcast).then_some(input))
+        .collect();
+    let generics: Vec<syn::Ident> = broadcast.iter()
+        .enumerate()
+        .filter(|(_, broadcast)| !**broadcast)
+        .map(|(k, _)| syn::Ident::new(&format!("Input{k}"), span))
+        .collect();
+    let buffer_types = input_types.iter()
+        .zip(broadcast)
+        .filter_map(|(input_type, broadcast)| (!broadcast).then_some(input_type));
+    let mut generic = generics.iter();
+    let self_types = input_types.iter().zip(broadcast).map(|(input_type, broadcast)| match broadcast {
+        true => input_type.to_token_stream(),
+        false => generic.next().to_token_stream(),
+    });
+    let self_inputs = inputs.iter().zip(broadcast).enumerate().map(|(k, (input, broadcast))| {
+        let k = syn::Index::from(k);
+        match broadcast {
+            true => quote::quote! { let #input = self.#k; },
+            false => quote::quote! { let #input = self.#k.as_ref(); },
+        }
+    });
+    let (first, rest) = buffers.split_first().expect("map functions have an input that is not broadcast");
+    let length_check = (!rest.is_empty()).then(|| quote::quote! {
+        if #( #rest.len() != n )||* {
+            return Err(spindle::range::Error::LengthMismatch)
+        }
+    });
+    let elements: Vec<TokenStream> = inputs.iter().zip(broadcast).map(|(input, broadcast)| match broadcast {
+        true => quote::quote! { #input },
+        false => quote::quote! { #input[i] },
+    }).collect();
+    let devs: Vec<syn::Ident> = buffers.iter()
+        .map(|input| syn::Ident::new(&format!("{input}_dev"), span))
+        .collect();
+    let args = inputs.iter().zip(broadcast).map(|(input, broadcast)| match broadcast {
+        true => quote::quote! { #input },
+        false => {
+            let dev = syn::Ident::new(&format!("{input}_dev"), span);
+            quote::quote! { &#dev }
+        },
+    });
 
+    let n = quote::quote! { n };
+    let config = attr.launch_config(&n);
+    let launch = quote::quote! {
+        #( let #devs = dev.htod_sync_copy(#buffers)?; )*
+        let mut out_dev = dev.alloc_zeros::<#return_type>(n)?;
+        let config = #config;
+        // SAFETY: `kernel` takes the input buffers (all of length `n`), the
+        // broadcast inputs, the output buffer and `n`, and only accesses the
+        // buffers below `n`
+        unsafe { f.launch(config, (#( #args, )* &mut out_dev, n)) }?;
+        dev.sync_reclaim(out_dev)?
+    };
+    let on_host = on_host(
+        quote::quote! { (0..n).map(|i| #name(#( #elements ),*)).collect::<Vec<#return_type>>() },
+        quote::quote! { (0..n).into_par_iter().map(|i| #name(#( #elements ),*)).collect::<Vec<#return_type>>() },
+    );
+    let out_host = launch_or_fallback(&ptx, launch, on_host);
+
+    let tuple_impl = quote::quote! {
+        impl< #( #generics: AsRef<[#buffer_types]> ),* > #trait_name for ( #( #self_types, )* ) {
+            type Returns = Vec<#return_type>;
+            #[allow(unexpected_cfgs)]
+            fn #name (&self) -> Result<Self::Returns, spindle::range::Error> {
+                #( #self_inputs )*
+                let n = #first.len();
+                #length_check
+                if n == 0 {
+                    return Ok(Vec::new())
+                }
+                let out_host = #out_host;
+                Ok(out_host)
+            }
+        }
+    };
+    Ok(quote::quote! {
+        #item
+        #map_trait
+        #tuple_impl
+    })
+}
+
+fn emit_reduce_kernel(attr: RangeAttributes, item: ReduceFn, kernels: &impl Kernels) -> TokenResult {
+    let Some(identity) = &attr.identity else {
+        let signature = item.0.sig.to_token_stream();
+        return Err(compile_error(&signature, "`reduce` functions need an `identity = ...` attribute"))
+    };
+    attr.reject_unused("reduce", &[KERNEL_ATTRIBUTES, &["identity"]].concat())?;
+    let item_type = item.input_type();
+    let lib_rs = reduce::lib_rs(&item_type.to_token_stream().to_string());
+    let ptx = kernels.ptx(&attr, &item, lib_rs)?;
+
+    let name = item.ident();
+    let trait_name = item.trait_name();
+
+    let reduce_trait = quote::quote! {
+        trait #trait_name {
+            fn #name (&self) -> Result<#item_type, spindle::range::Error>;
+        }
+    };
+
+    // every pass folds its input into one value per block, until one block remains
+    let block_dim = attr.block_dim.unwrap_or(256);
+    let max_grid_dim = attr.grid_dim.unwrap_or(1024);
+    let launch = quote::quote! {
+        let block_dim: u32 = #block_dim;
+        let mut n = n;
+        let mut in_dev = dev.htod_sync_copy(self)?;
+        loop {
+            let grid_dim = n.div_ceil(block_dim as usize).clamp(1, #max_grid_dim as usize) as u32;
+            let mut scratch_dev = dev.alloc_zeros::<#item_type>(grid_dim as usize * block_dim as usize)?;
+            let mut out_dev = dev.alloc_zeros::<#item_type>(grid_dim as usize)?;
+            let config = LaunchConfig {
+                grid_dim: (grid_dim, 1, 1),
+                block_dim: (block_dim, 1, 1),
+                shared_mem_bytes: 0,
+            };
+            // SAFETY: `kernel` takes the input buffer and its length, a scratch
+            // buffer of `block_dim` values per block and an output buffer of
+            // one value per block, and only accesses them within those bounds
+            unsafe { f.clone().launch(config, (&in_dev, &mut scratch_dev, &mut out_dev, n, identity)) }?;
+            if grid_dim == 1 {
+                break dev.dtoh_sync_copy(&out_dev)?[0]
+            }
+            in_dev = out_dev;
+            n = grid_dim as usize;
+        }
+    };
+    let on_host = on_host(
+        quote::quote! { self.iter().copied().fold(identity, #name) },
+        quote::quote! { self.par_iter().copied().reduce(|| identity, #name) },
+    );
+    let out_host = launch_or_fallback(&ptx, launch, on_host);
+
+    let slice_impl = quote::quote! {
+        impl #trait_name for [#item_type] {
+            #[allow(unexpected_cfgs)]
+            fn #name (&self) -> Result<#item_type, spindle::range::Error> {
+                let identity: #item_type = #identity;
+                let n = self.len();
+                if n == 0 {
+                    return Ok(identity)
+                }
+                let out_host = #out_host;
+                Ok(out_host)
+            }
+        }
+    };
+    Ok(quote::quote! {
+        #item
+        #reduce_trait
+        #slice_impl
+    })
+}
+
+fn emit_scan_kernel(attr: RangeAttributes, item: ReduceFn, kernels: &impl Kernels) -> TokenResult {
+    let Some(identity) = &attr.identity else {
+        let signature = item.0.sig.to_token_stream();
+        return Err(compile_error(&signature, "`scan` functions need an `identity = ...` attribute"))
+    };
+    attr.reject_unused("scan", &[KERNEL_ATTRIBUTES, &["identity", "exclusive"]].concat())?;
+    let exclusive = attr.exclusive.as_ref().is_some_and(|exclusive| exclusive.value);
+    let item_type = item.input_type();
+    let lib_rs = scan::lib_rs(&item_type.to_token_stream().to_string(), exclusive);
+    let ptx = kernels.ptx(&attr, &item, lib_rs)?;
+
+    let name = item.ident();
+    let trait_name = item.trait_name();
+
+    let scan_trait = quote::quote! {
+        trait #trait_name {
+            fn #name (&self) -> Result<Vec<#item_type>, spindle::range::Error>;
+        }
+    };
+
+    // the block totals are few enough to scan on the host between the phases
+    let block_dim = attr.block_dim.unwrap_or(256);
+    let max_grid_dim = attr.grid_dim.unwrap_or(1024);
+    let launch = quote::quote! {
+        let block_dim: u32 = #block_dim;
+        let grid_dim = n.div_ceil(block_dim as usize).clamp(1, #max_grid_dim as usize) as u32;
+        let in_dev = dev.htod_sync_copy(self)?;
+        let mut out_dev = dev.alloc_zeros::<#item_type>(n)?;
+        let mut scratch_dev = dev.alloc_zeros::<#item_type>(grid_dim as usize * block_dim as usize)?;
+        let mut sums_dev = dev.alloc_zeros::<#item_type>(grid_dim as usize)?;
+        let config = LaunchConfig {
+            grid_dim: (grid_dim, 1, 1),
+            block_dim: (block_dim, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        // SAFETY: `kernel` takes the input and output buffers and their common
+        // length, a scratch buffer of `block_dim` values per block and a buffer
+        // of one value per block, and only accesses them within those bounds
+        unsafe { f.clone().launch(config, (&in_dev, &mut out_dev, &mut scratch_dev, &mut sums_dev, n, identity, 0u32)) }?;
+        if grid_dim > 1 {
+            let mut total = identity;
+            let offsets: Vec<#item_type> = dev.dtoh_sync_copy(&sums_dev)?
+                .into_iter()
+                .map(|sum| {
+                    let offset = total;
+                    total = #name(total, sum);
+                    offset
+                })
+                .collect();
+            dev.htod_sync_copy_into(&offsets, &mut sums_dev)?;
+            // SAFETY: as above
+            unsafe { f.launch(config, (&in_dev, &mut out_dev, &mut scratch_dev, &mut sums_dev, n, identity, 1u32)) }?;
+        }
+        dev.sync_reclaim(out_dev)?
+    };
+    let prefix = match exclusive {
+        true => quote::quote! { offset },
+        false => quote::quote! { total },
+    };
+    // rayon has no scan, so the host always runs serially
+    let on_host = quote::quote! {{
+        let mut total = identity;
+        self.iter()
+            .map(|x| {
+                let offset = total;
+                total = #name(total, *x);
+                #prefix
+            })
+            .collect::<Vec<#item_type>>()
+    }};
+    let out_host = launch_or_fallback(&ptx, launch, on_host);
+
+    let slice_impl = quote::quote! {
+        impl #trait_name for [#item_type] {
+            fn #name (&self) -> Result<Vec<#item_type>, spindle::range::Error> {
+                let identity: #item_type = #identity;
+                let n = self.len();
+                if n == 0 {
+                    return Ok(Vec::new())
+                }
+                let out_host = #out_host;
+                Ok(out_host)
+            }
         }
     };
     Ok(quote::quote! {
         #item
-        #range_trait
-        #int_impl
-        #launcher
+        #scan_trait
+        #slice_impl
     })
 }
 
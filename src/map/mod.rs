@@ -0,0 +1,62 @@
+use crate::range::{grid_stride, PRELUDE};
+
+pub(super) fn lib_rs(input_type: &str, return_type: &str) -> String {
+    format!("{PRELUDE}{LIB_RS}")
+        .replace("{grid_stride}", &grid_stride(&["let value = device::device(*input.add(i));", "*output.add(i) = value;"]))
+        .replace("{input_type}", input_type)
+        .replace("{return_type}", return_type)
+}
+
+pub(super) fn in_place_lib_rs(input_type: &str) -> String {
+    format!("{PRELUDE}{IN_PLACE_LIB_RS}")
+        .replace("{grid_stride}", &grid_stride(&["device::device(&mut *data.add(i));"]))
+        .replace("{input_type}", input_type)
+}
+
+/// `inputs` holds the type of each input and whether it is broadcast, i.e.
+/// passed to every thread by value rather than read from a buffer.
+pub(super) fn zip_lib_rs(inputs: &[(String, bool)], return_type: &str) -> String {
+    let params: String = inputs.iter()
+        .enumerate()
+        .map(|(k, (input_type, broadcast))| match broadcast {
+            true => format!("input_{k}: {input_type}, "),
+            false => format!("input_{k}: *const {input_type}, "),
+        })
+        .collect();
+    let args: Vec<String> = inputs.iter()
+        .enumerate()
+        .map(|(k, (_, broadcast))| match broadcast {
+            true => format!("input_{k}"),
+            false => format!("*input_{k}.add(i)"),
+        })
+        .collect();
+    format!("{PRELUDE}{ZIP_LIB_RS}")
+        .replace("{grid_stride}", &grid_stride(&["let value = device::device({args});", "*output.add(i) = value;"]))
+        .replace("{inputs}", &params)
+        .replace("{args}", &args.join(", "))
+        .replace("{return_type}", return_type)
+}
+
+static LIB_RS: &str = r#"
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel(input: *const {input_type}, output: *mut {return_type}, size: usize)
+{
+{grid_stride}
+}
+"#;
+
+static IN_PLACE_LIB_RS: &str = r#"
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel(data: *mut {input_type}, size: usize)
+{
+{grid_stride}
+}
+"#;
+
+static ZIP_LIB_RS: &str = r#"
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel({inputs}output: *mut {return_type}, size: usize)
+{
+{grid_stride}
+}
+"#;
@@ -1,6 +1,6 @@
-use syn::{parse::{Parse, ParseStream}, ItemFn, Result};
+use syn::{parse::{Parse, ParseStream}, punctuated::Punctuated, ItemFn, MetaNameValue, Result, Token};
 
-use crate::{RangeAttributes, RangeFn};
+use crate::{toolchain, DeviceItem, Layout, MapFn, PtxSource, RangeAttributes, RangeFn, ReduceFn, ReduceOp};
 
 static NO_ATTRIBUTES: &str = "attributes are not yet supported";
 static NO_GENERICS: &str = "generic functions are not yet supported";
@@ -9,91 +9,393 @@ static NO_WHERE_CLAUSE: &str = "where clauses are not supported";
 static EXACTLY_ONE_INPUT: &str = "range functions have exactly one integer input";
 static ONLY_INTEGERS: &str = "range functions take integer types (isize, usize, i32, u32, etc.)";
 static NO_RETURN: &str = "range functions have a return type";
-static ONLY_PRIMITIVE_RETURNS: &str = "range functions currently return primitive number types (i32, usize, f32, etc.)";
-static ONLY_I32: &str = "range functions currently only admit i32";
+static ONLY_PRIMITIVE_RETURNS: &str = "range functions return primitive number types (i32, usize, f32, etc.), tuples or arrays of them, or `#[spindle::device]` structs";
+static MAP_AT_LEAST_ONE_INPUT: &str = "map functions have at least one input";
+static MAP_TOO_MANY_INPUTS: &str = "map functions have at most 10 inputs";
+static MAP_NOT_ALL_BROADCAST: &str = "map functions have at least one input that is not `#[broadcast]`";
+static MAP_ONLY_PRIMITIVES: &str = "map functions take primitive number types (i32, usize, f32, etc.), or `&mut` to one to map in place";
+static MAP_IN_PLACE_NO_RETURN: &str = "in-place map functions return nothing";
+static MAP_NO_RETURN: &str = "map functions have a return type";
+static MAP_ONLY_PRIMITIVE_RETURNS: &str = "map functions currently return primitive number types (i32, usize, f32, etc.)";
+static REDUCE_TWO_INPUTS: &str = "reduce and scan functions have exactly two inputs of the same type";
+static REDUCE_ONLY_PRIMITIVES: &str = "reduce and scan functions combine primitive number types (i32, usize, f32, etc.)";
+static REDUCE_SAME_RETURN: &str = "reduce and scan functions return the type of their inputs";
+static DEVICE_ITEMS: &str = "`#[spindle::device]` takes a struct, fn, const or static";
+static DEVICE_IMMUTABLE: &str = "device statics are immutable";
+static DEVICE_NO_GENERICS: &str = "device structs have no generics";
+static DEVICE_REPR_C: &str = "device structs are `#[repr(C)]`";
+static DEVICE_ONLY_PRIMITIVES: &str = "device structs have primitive number fields (i32, usize, f32, etc.)";
+static UNKNOWN_ATTRIBUTE: &str = "unknown attribute (expected `block_dim`, `threads_per_block`, `grid_dim`, `ptx`, `home`, `toolchain`, `uses`, `identity`, `reduce`, `exclusive` or `layout`)";
+static DUPLICATE_ATTRIBUTE: &str = "attribute is already set";
+static REDUCE_OPS: &str = "expected `reduce = add`, `mul`, `min` or `max`";
+static EXCLUSIVE_BOOL: &str = "expected `exclusive = true` or `exclusive = false`";
+static ONLY_U32_LITERALS: &str = "launch dimensions are integer literals";
+static BLOCK_DIM_RANGE: &str = "block_dim must be between 1 and 1024";
+static GRID_DIM_RANGE: &str = "grid_dim must be between 1 and 2147483647";
+static PTX_SOURCES: &str = "ptx is either \"embed\" or \"file\"";
+static LAYOUTS: &str = "layout is either \"aos\" (tuples or arrays per index) or \"soa\" (a buffer per component)";
+static HOME_PATH: &str = "home is a string literal path";
+static USES_NAMES: &str = "uses is a list of `#[spindle::device]` item names, such as `[helper, SCALE]`";
+static TOOLCHAIN_NIGHTLY: &str = "toolchain is a nightly, such as \"nightly-2024-01-31\"";
 
 impl Parse for RangeAttributes {
     fn parse(input: ParseStream) -> Result<Self> {
-        if input.is_empty() {
-            Ok(Self)
-        } else {
-            Err(input.error(NO_ATTRIBUTES))
+        let mut attributes = Self::default();
+        let pairs = Punctuated::<MetaNameValue, Token![,]>::parse_terminated(input)?;
+        for pair in pairs {
+            attributes.keys.extend(pair.path.get_ident().cloned());
+            let key = pair.path.get_ident().map(ToString::to_string);
+            match key.as_deref() {
+                Some("block_dim" | "threads_per_block") => {
+                    let block_dim = launch_dim(&pair, &mut attributes.block_dim)?;
+                    if !(1..=1024).contains(&block_dim) {
+                        return Err(syn::Error::new_spanned(&pair.value, BLOCK_DIM_RANGE));
+                    }
+                },
+                Some("grid_dim") => {
+                    let grid_dim = launch_dim(&pair, &mut attributes.grid_dim)?;
+                    // CUDA caps `gridDim.x` at 2^31 - 1
+                    if !(1..=i32::MAX as u32).contains(&grid_dim) {
+                        return Err(syn::Error::new_spanned(&pair.value, GRID_DIM_RANGE));
+                    }
+                },
+                Some("ptx") => {
+                    let ptx = string_value(&pair, attributes.ptx.is_some(), PTX_SOURCES)?;
+                    attributes.ptx = match ptx.value().as_str() {
+                        "embed" => Some(PtxSource::Embed),
+                        "file" => Some(PtxSource::File),
+                        _ => return Err(syn::Error::new_spanned(ptx, PTX_SOURCES)),
+                    };
+                },
+                Some("layout") => {
+                    let layout = string_value(&pair, attributes.layout.is_some(), LAYOUTS)?;
+                    attributes.layout = match layout.value().as_str() {
+                        "aos" => Some((Layout::Aos, layout.clone())),
+                        "soa" => Some((Layout::Soa, layout.clone())),
+                        _ => return Err(syn::Error::new_spanned(layout, LAYOUTS)),
+                    };
+                },
+                Some("home") => {
+                    let home = string_value(&pair, attributes.home.is_some(), HOME_PATH)?;
+                    attributes.home = Some(home.value());
+                },
+                Some("toolchain") => {
+                    let toolchain = string_value(&pair, attributes.toolchain.is_some(), TOOLCHAIN_NIGHTLY)?;
+                    if !toolchain::is_nightly(&toolchain.value()) {
+                        return Err(syn::Error::new_spanned(toolchain, TOOLCHAIN_NIGHTLY));
+                    }
+                    attributes.toolchain = Some(toolchain.value());
+                },
+                Some("uses") => {
+                    if attributes.uses.is_some() {
+                        return Err(syn::Error::new_spanned(&pair.path, DUPLICATE_ATTRIBUTE));
+                    }
+                    let syn::Expr::Array(names) = &pair.value else {
+                        return Err(syn::Error::new_spanned(&pair.value, USES_NAMES));
+                    };
+                    let uses = names.elems.iter()
+                        .map(|name| match name {
+                            syn::Expr::Path(path) if path.qself.is_none() && path.attrs.is_empty() => path.path.get_ident().cloned(),
+                            _ => None,
+                        }.ok_or_else(|| syn::Error::new_spanned(name, USES_NAMES)))
+                        .collect::<Result<_>>()?;
+                    attributes.uses = Some(uses);
+                },
+                Some("identity") => {
+                    if attributes.identity.is_some() {
+                        return Err(syn::Error::new_spanned(&pair.path, DUPLICATE_ATTRIBUTE));
+                    }
+                    attributes.identity = Some(pair.value);
+                },
+                Some("reduce") => {
+                    if attributes.reduce.is_some() {
+                        return Err(syn::Error::new_spanned(&pair.path, DUPLICATE_ATTRIBUTE));
+                    }
+                    let op = match &pair.value {
+                        syn::Expr::Path(path) if path.qself.is_none() => path.path.get_ident(),
+                        _ => None,
+                    };
+                    let op = op.ok_or_else(|| syn::Error::new_spanned(&pair.value, REDUCE_OPS))?;
+                    let reduce = match op.to_string().as_str() {
+                        "add" => ReduceOp::Add,
+                        "mul" => ReduceOp::Mul,
+                        "min" => ReduceOp::Min,
+                        "max" => ReduceOp::Max,
+                        _ => return Err(syn::Error::new_spanned(op, REDUCE_OPS)),
+                    };
+                    attributes.reduce = Some((reduce, op.clone()));
+                },
+                Some("exclusive") => {
+                    if attributes.exclusive.is_some() {
+                        return Err(syn::Error::new_spanned(&pair.path, DUPLICATE_ATTRIBUTE));
+                    }
+                    attributes.exclusive = match &pair.value {
+                        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Bool(exclusive), .. }) => Some(exclusive.clone()),
+                        _ => return Err(syn::Error::new_spanned(&pair.value, EXCLUSIVE_BOOL)),
+                    };
+                },
+                _ => return Err(syn::Error::new_spanned(&pair.path, UNKNOWN_ATTRIBUTE)),
+            }
         }
+        Ok(attributes)
     }
 }
 
-impl Parse for RangeFn {
+fn launch_dim(pair: &MetaNameValue, dim: &mut Option<u32>) -> Result<u32> {
+    if dim.is_some() {
+        return Err(syn::Error::new_spanned(&pair.path, DUPLICATE_ATTRIBUTE));
+    }
+    let value = match &pair.value {
+        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(value), .. }) => value,
+        value => return Err(syn::Error::new_spanned(value, ONLY_U32_LITERALS)),
+    };
+    let value = value.base10_parse::<u32>()?;
+    *dim = Some(value);
+    Ok(value)
+}
+
+fn string_value<'a>(pair: &'a MetaNameValue, duplicate: bool, msg: &str) -> Result<&'a syn::LitStr> {
+    if duplicate {
+        return Err(syn::Error::new_spanned(&pair.path, DUPLICATE_ATTRIBUTE));
+    }
+    match &pair.value {
+        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(value), .. }) => Ok(value),
+        value => Err(syn::Error::new_spanned(value, msg)),
+    }
+}
+
+/// cudarc launches kernels with at most 12 parameters, two of which are the
+/// output buffer and its length.
+static MAP_MAX_INPUTS: usize = 10;
+static INTEGERS: &[&str] = &["isize", "usize", "i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64"];
+pub(super) static PRIMITIVES: &[&str] = &["isize", "usize", "f32", "f64", "i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64"];
+
+/// Rejects attributes, generics and where clauses, which no kernel supports.
+fn plain_fn(item: &ItemFn) -> Result<()> {
+    if let Some(attr) = item.attrs.first() {
+        return Err(syn::Error::new_spanned(attr, NO_ATTRIBUTES));
+    }
+    if !item.sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&item.sig.generics, NO_GENERICS));
+    }
+    if let Some(where_clause) = &item.sig.generics.where_clause {
+        return Err(syn::Error::new_spanned(where_clause, NO_WHERE_CLAUSE));
+    }
+    Ok(())
+}
+
+/// The name of a single-segment path type such as `f32`, if `ty` is one.
+fn type_name(ty: &syn::Type) -> Option<String> {
+    let path = match ty {
+        syn::Type::Path(path) if path.qself.is_none() => path,
+        _ => return None,
+    };
+    match path.path.segments.len() {
+        1 if path.path.segments[0].arguments.is_empty() => Some(path.path.segments[0].ident.to_string()),
+        _ => None,
+    }
+}
+
+/// The length of `[T; N]`, if `N` is a non-zero integer literal.
+pub(super) fn array_len(array: &syn::TypeArray) -> Option<usize> {
+    match &array.len {
+        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(len), .. }) => len.base10_parse().ok().filter(|len| *len > 0),
+        _ => None,
+    }
+}
+
+fn primitive(ty: &syn::Type) -> bool {
+    type_name(ty).is_some_and(|name| PRIMITIVES.contains(&name.as_str()))
+}
+
+impl Parse for MapFn {
     fn parse(input: ParseStream) -> Result<Self> {
-        let range_fn: ItemFn = input.parse()?;
-        if !range_fn.attrs.is_empty() {
-            return Err(input.error(NO_ATTRIBUTES));
+        let mut map_fn: ItemFn = input.parse()?;
+        plain_fn(&map_fn)?;
+        match map_fn.sig.inputs.len() {
+            0 => return Err(syn::Error::new(map_fn.sig.paren_token.span.join(), MAP_AT_LEAST_ONE_INPUT)),
+            n if n > MAP_MAX_INPUTS => return Err(syn::Error::new_spanned(&map_fn.sig.inputs, MAP_TOO_MANY_INPUTS)),
+            _ => {},
+        }
+        let in_place = map_fn.sig.inputs.len() == 1 && matches!(
+            map_fn.sig.inputs.first(),
+            Some(syn::FnArg::Typed(arg)) if matches!(arg.ty.as_ref(), syn::Type::Reference(r) if r.mutability.is_some())
+        );
+        let mut broadcast = Vec::with_capacity(map_fn.sig.inputs.len());
+        for arg in map_fn.sig.inputs.iter_mut() {
+            let arg = match arg {
+                syn::FnArg::Receiver(receiver) => return Err(syn::Error::new_spanned(receiver, NOT_A_METHOD)),
+                syn::FnArg::Typed(arg) => arg,
+            };
+            // `#[broadcast]` is consumed here, so neither the host nor the kernel crate sees it
+            let attrs = arg.attrs.len();
+            arg.attrs.retain(|attr| !matches!(&attr.meta, syn::Meta::Path(path) if path.is_ident("broadcast")));
+            broadcast.push(arg.attrs.len() < attrs);
+            if let Some(attr) = arg.attrs.first() {
+                return Err(syn::Error::new_spanned(attr, NO_ATTRIBUTES));
+            }
+            let input_type = match arg.ty.as_ref() {
+                syn::Type::Reference(reference) if in_place => reference.elem.as_ref(),
+                input_type => input_type,
+            };
+            if !type_name(input_type).is_some_and(|ty| PRIMITIVES.contains(&ty.as_str())) {
+                return Err(syn::Error::new_spanned(&arg.ty, MAP_ONLY_PRIMITIVES));
+            }
         }
-        if !range_fn.sig.generics.params.is_empty() {
-            return Err(input.error(NO_GENERICS));
+        if broadcast.iter().all(|broadcast| *broadcast) {
+            return Err(syn::Error::new_spanned(&map_fn.sig.inputs, MAP_NOT_ALL_BROADCAST));
         }
-        if !range_fn.sig.generics.where_clause.is_none() {
-            return Err(input.error(NO_WHERE_CLAUSE));
+        if in_place {
+            return match &map_fn.sig.output {
+                syn::ReturnType::Default => Ok(Self(map_fn, broadcast)),
+                output => Err(syn::Error::new_spanned(output, MAP_IN_PLACE_NO_RETURN)),
+            }
         }
+        let output = match &map_fn.sig.output {
+            syn::ReturnType::Default => return Err(syn::Error::new_spanned(&map_fn.sig, MAP_NO_RETURN)),
+            syn::ReturnType::Type(_, output) => output,
+        };
+        if !type_name(output).is_some_and(|ty| PRIMITIVES.contains(&ty.as_str())) {
+            return Err(syn::Error::new_spanned(output, MAP_ONLY_PRIMITIVE_RETURNS));
+        }
+        Ok(Self(map_fn, broadcast))
+    }
+}
+
+impl Parse for ReduceFn {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let reduce_fn: ItemFn = input.parse()?;
+        plain_fn(&reduce_fn)?;
+        let mut types = Vec::with_capacity(2);
+        for arg in &reduce_fn.sig.inputs {
+            let arg = match arg {
+                syn::FnArg::Receiver(receiver) => return Err(syn::Error::new_spanned(receiver, NOT_A_METHOD)),
+                syn::FnArg::Typed(arg) => arg,
+            };
+            if let Some(attr) = arg.attrs.first() {
+                return Err(syn::Error::new_spanned(attr, NO_ATTRIBUTES));
+            }
+            types.push(&arg.ty);
+        }
+        let item_type = match types.as_slice() {
+            [left, right] if type_name(left).is_some_and(|left| type_name(right).is_some_and(|right| left.eq(&right))) => left,
+            _ => return Err(syn::Error::new(reduce_fn.sig.paren_token.span.join(), REDUCE_TWO_INPUTS)),
+        };
+        if !primitive(item_type) {
+            return Err(syn::Error::new_spanned(item_type, REDUCE_ONLY_PRIMITIVES));
+        }
+        let output = match &reduce_fn.sig.output {
+            syn::ReturnType::Default => return Err(syn::Error::new_spanned(&reduce_fn.sig, REDUCE_SAME_RETURN)),
+            syn::ReturnType::Type(_, output) => output,
+        };
+        if type_name(output) != type_name(item_type) {
+            return Err(syn::Error::new_spanned(output, REDUCE_SAME_RETURN));
+        }
+        Ok(Self(reduce_fn))
+    }
+}
+
+impl Parse for DeviceItem {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let item: syn::Item = input.parse()?;
+        match &item {
+            syn::Item::Struct(item) => device_struct(item)?,
+            syn::Item::Fn(_) | syn::Item::Const(_) => {},
+            syn::Item::Static(item) if matches!(item.mutability, syn::StaticMutability::None) => {},
+            syn::Item::Static(item) => return Err(syn::Error::new_spanned(&item.mutability, DEVICE_IMMUTABLE)),
+            item => return Err(syn::Error::new_spanned(item, DEVICE_ITEMS)),
+        }
+        Ok(Self(item))
+    }
+}
+
+/// Structs are shared with the host, which needs their layout to be fixed and
+/// all zeros to be a valid value.
+fn device_struct(item: &syn::ItemStruct) -> Result<()> {
+    if !item.generics.params.is_empty() || item.generics.where_clause.is_some() {
+        return Err(syn::Error::new_spanned(&item.generics, DEVICE_NO_GENERICS));
+    }
+    let repr_c = item.attrs.iter()
+        .filter(|attr| attr.path().is_ident("repr"))
+        .filter_map(|attr| attr.meta.require_list().ok())
+        .flat_map(|list| list.tokens.clone())
+        .any(|token| matches!(token, proc_macro2::TokenTree::Ident(ident) if ident == "C"));
+    if !repr_c {
+        return Err(syn::Error::new_spanned(&item.ident, DEVICE_REPR_C));
+    }
+    if let Some(field) = item.fields.iter().find(|field| !primitive(&field.ty)) {
+        return Err(syn::Error::new_spanned(&field.ty, DEVICE_ONLY_PRIMITIVES));
+    }
+    Ok(())
+}
+
+impl Parse for RangeFn {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let range_fn: ItemFn = input.parse()?;
+        plain_fn(&range_fn)?;
+        let inputs_span = range_fn.sig.paren_token.span.join();
         if range_fn.sig.inputs.is_empty() {
-            return Err(input.error(EXACTLY_ONE_INPUT));
+            return Err(syn::Error::new(inputs_span, EXACTLY_ONE_INPUT));
         }
         let mut inputs = range_fn.sig.inputs.iter();
         let arg = inputs.next();
         let arg = match (arg, inputs.next()) {
             (None, _) | (Some(_), Some(_)) =>
-                return Err(input.error(EXACTLY_ONE_INPUT)),
+                return Err(syn::Error::new(inputs_span, EXACTLY_ONE_INPUT)),
             (Some(arg), None) => arg,
         };
         let arg = match arg {
-            syn::FnArg::Receiver(_) => return Err(input.error(NOT_A_METHOD)),
+            syn::FnArg::Receiver(receiver) => return Err(syn::Error::new_spanned(receiver, NOT_A_METHOD)),
             syn::FnArg::Typed(arg) => arg,
         };
-        if !arg.attrs.is_empty() {
-            return Err(input.error(NO_ATTRIBUTES));
+        if let Some(attr) = arg.attrs.first() {
+            return Err(syn::Error::new_spanned(attr, NO_ATTRIBUTES));
         }
+        let only_integers = || syn::Error::new_spanned(&arg.ty, ONLY_INTEGERS);
         let int_type = match arg.ty.as_ref() {
             syn::Type::Path(path) => path,
-            _ => return Err(input.error(ONLY_INTEGERS)),
+            _ => return Err(only_integers()),
         };
         if int_type.qself.is_some() {
-            return Err(input.error(ONLY_INTEGERS));
+            return Err(only_integers());
         }
         let int_type = match int_type.path.segments.len() {
             1 => &int_type.path.segments[0],
-            _ => return Err(input.error(ONLY_INTEGERS)),
+            _ => return Err(only_integers()),
         };
         if !int_type.arguments.is_empty() {
-            return Err(input.error(ONLY_INTEGERS));
+            return Err(only_integers());
         }
         let int_type = int_type.ident.to_string();
-        if !["isize", "usize", "i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64"].contains(&int_type.as_str()) {
-            return Err(input.error(ONLY_INTEGERS));
-        }
-        if int_type.ne("i32") {
-            return Err(input.error(ONLY_I32));
+        if !INTEGERS.contains(&int_type.as_str()) {
+            return Err(only_integers());
         }
 
         let output = match &range_fn.sig.output {
-            syn::ReturnType::Default => return Err(input.error(NO_RETURN)),
-            syn::ReturnType::Type(_, output) => *output.clone(),
+            syn::ReturnType::Default => return Err(syn::Error::new_spanned(&range_fn.sig, NO_RETURN)),
+            syn::ReturnType::Type(_, output) => output.as_ref(),
         };
+        let only_primitive_returns = || syn::Error::new_spanned(output, ONLY_PRIMITIVE_RETURNS);
         let output = match output {
             syn::Type::Path(path) => path,
-            _ => return Err(input.error(ONLY_PRIMITIVE_RETURNS)),
+            // tuples go up to 12, like the standard library's trait impls for them
+            syn::Type::Tuple(tuple) if (1..=12).contains(&tuple.elems.len()) && tuple.elems.iter().all(primitive) => {
+                return Ok(Self(range_fn))
+            },
+            syn::Type::Array(array) if primitive(&array.elem) && array_len(array).is_some() => {
+                return Ok(Self(range_fn))
+            },
+            _ => return Err(only_primitive_returns()),
         };
         if output.qself.is_some() {
-            return Err(input.error(ONLY_PRIMITIVE_RETURNS));
+            return Err(only_primitive_returns());
         }
         let output_type = match output.path.segments.len() {
             1 => &output.path.segments[0],
-            _ => return Err(input.error(ONLY_PRIMITIVE_RETURNS)),
+            _ => return Err(only_primitive_returns()),
         };
         if !output_type.arguments.is_empty() {
-            return Err(input.error(ONLY_PRIMITIVE_RETURNS));
-        }
-        let output_type = output_type.ident.to_string();
-        if !["isize", "usize", "f32", "f64", "i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64"].contains(&output_type.as_str()) {
-            return Err(input.error(ONLY_PRIMITIVE_RETURNS));
+            return Err(only_primitive_returns());
         }
 
         Ok(Self(range_fn))
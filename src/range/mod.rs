@@ -13,7 +13,7 @@ bench = false
 
 pub(super) static RUST_TOOLCHAIN_TOML: &str = r#"
 [toolchain]
-channel = "nightly"
+channel = "{toolchain}"
 "#;
 
 pub(super) static CONFIG_TOML: &str = r#"
@@ -25,7 +25,52 @@ rustflags = ["--emit", "asm"]
 color = "always" # "auto"
 "#;
 
-pub(super) static LIB_RS: &str = r#"
+pub(super) fn lib_rs(index_type: &str, return_type: &str) -> String {
+    output_lib_rs(index_type, &format!("output: *mut {return_type}"), "*output.add(i) = value;")
+}
+
+/// Writes each tuple or array return value as a `#[repr(C)]` struct with the
+/// same fields, whose layout, unlike a tuple's, the host can rely on.
+pub(super) fn aos_lib_rs(index_type: &str, components: &[String], array: bool) -> String {
+    let (fields, value) = match array {
+        true => (format!("pub [{}; {}]", components[0], components.len()), "value".to_string()),
+        false => (
+            components.iter().map(|ty| format!("pub {ty}")).collect::<Vec<_>>().join(", "),
+            (0..components.len()).map(|k| format!("value.{k}")).collect::<Vec<_>>().join(", "),
+        ),
+    };
+    let returns = RETURNS.replace("{fields}", &fields);
+    let lib_rs = output_lib_rs(index_type, "output: *mut Returns", &format!("*output.add(i) = Returns({value});"));
+    format!("{lib_rs}{returns}")
+}
+
+/// Writes each component of a tuple or array return value to its own buffer.
+pub(super) fn soa_lib_rs(index_type: &str, components: &[String], array: bool) -> String {
+    let outputs = components.iter().enumerate()
+        .map(|(k, ty)| format!("output_{k}: *mut {ty}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let store = (0..components.len())
+        .map(|k| match array {
+            true => format!("*output_{k}.add(i) = value[{k}];"),
+            false => format!("*output_{k}.add(i) = value.{k};"),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    output_lib_rs(index_type, &outputs, &store)
+}
+
+fn output_lib_rs(index_type: &str, outputs: &str, store: &str) -> String {
+    format!("{PRELUDE}{LIB_RS}")
+        .replace("{grid_stride}", &grid_stride(&["let value = device::device(i as {index_type});", "{store}"]))
+        .replace("{index_type}", index_type)
+        .replace("{outputs}", outputs)
+        .replace("{store}", store)
+}
+
+/// The crate attributes, imports and panic handler every kernel crate's
+/// `lib.rs` starts with.
+pub(super) static PRELUDE: &str = r#"
 #![no_std]
 #![feature(abi_ptx)]
 #![feature(stdsimd)]
@@ -38,25 +83,41 @@ use core::arch::nvptx::*;
 fn my_panic(_: &core::panic::PanicInfo) -> ! {
     loop {}
 }
+"#;
 
-#[no_mangle]
-pub unsafe extern "ptx-kernel" fn kernel(output: *mut i32, size: i32)
-{
-    let thread_id: i32 = _thread_idx_x();
-    let block_id: i32 = _block_idx_x();
+/// The loop every elementwise kernel runs, which strides over the grid
+/// running `body`, one statement per line, for each index `i` below `size`.
+pub(super) fn grid_stride(body: &[&str]) -> String {
+    GRID_STRIDE.replace("{body}", &body.join("\n        "))
+}
+
+static GRID_STRIDE: &str = r#"    let thread_id: usize = _thread_idx_x() as usize;
+    let block_id: usize = _block_idx_x() as usize;
     
-    let block_dim: i32 = _block_dim_x();
-    let grid_dim: i32 = _grid_dim_x();
+    let block_dim: usize = _block_dim_x() as usize;
+    let grid_dim: usize = _grid_dim_x() as usize;
     
-    let n_threads: i32 = block_dim * grid_dim;
-    let thread_index: i32 =  thread_id + block_id * block_dim;
+    let n_threads: usize = block_dim * grid_dim;
+    let thread_index: usize =  thread_id + block_id * block_dim;
     
-    let mut i: i32 = thread_index;
+    let mut i: usize = thread_index;
     while i < size {
-        let value = device::device(i);
-        *output.offset(i as isize) = value;
-        i = i.wrapping_add(n_threads);
-    }
+        {body}
+        i = match i.checked_add(n_threads) {
+            Some(i) => i,
+            None => break,
+        };
+    }"#;
+
+static LIB_RS: &str = r#"
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel({outputs}, size: {index_type})
+{
+    // the grid-stride loop runs in `usize` so that neither `n_threads` nor `i`
+    // can overflow narrow index types, and wide ones never wrap past `size`
+    let size: usize = if size > 0 { size as usize } else { 0 };
+
+{grid_stride}
     // while i < end && i < size {
     //     let value = device::device(i);
     //     *output.offset(i as isize) = value;
@@ -64,3 +125,9 @@ pub unsafe extern "ptx-kernel" fn kernel(output: *mut i32, size: i32)
     // }
 }
 "#;
+
+static RETURNS: &str = r#"
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Returns({fields});
+"#;
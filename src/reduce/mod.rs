@@ -0,0 +1,117 @@
+use crate::range::PRELUDE;
+
+pub(super) fn lib_rs(item_type: &str) -> String {
+    format!("{PRELUDE}{LIB_RS}{CHUNK}{REDUCE_BLOCK}")
+        .replace("{item_type}", item_type)
+}
+
+/// A range kernel fused with a reduction by `combine`, an expression in `a`
+/// and `b`, so that the mapped values are never written out.
+pub(super) fn range_lib_rs(index_type: &str, return_type: &str, combine: &str) -> String {
+    format!("{PRELUDE}{RANGE_LIB_RS}{CHUNK}{REDUCE_BLOCK}")
+        .replace("{index_type}", index_type)
+        .replace("{item_type}", return_type)
+        .replace("{combine}", combine)
+}
+
+/// One pass of a reduction: each block folds its share of `input` into
+/// `output[block_id]`, so the host relaunches over the partial results until
+/// one block remains.
+static LIB_RS: &str = r#"
+use device::device as combine;
+
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel(
+    input: *const {item_type},
+    scratch: *mut {item_type},
+    output: *mut {item_type},
+    size: usize,
+    identity: {item_type},
+)
+{
+    let (start, end) = chunk(size);
+    let mut value = identity;
+    let mut i: usize = start;
+    while i < end {
+        value = combine(value, *input.add(i));
+        i += 1;
+    }
+    reduce_block(scratch, output, value);
+}
+"#;
+
+/// Maps each index with `device::device` and folds the values as it goes,
+/// leaving one partial result per block for the host to finish.
+static RANGE_LIB_RS: &str = r#"
+fn combine(a: {item_type}, b: {item_type}) -> {item_type} {
+    {combine}
+}
+
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel(
+    scratch: *mut {item_type},
+    output: *mut {item_type},
+    size: {index_type},
+    identity: {item_type},
+)
+{
+    let size: usize = if size > 0 { size as usize } else { 0 };
+    let (start, end) = chunk(size);
+    let mut value = identity;
+    let mut i: usize = start;
+    while i < end {
+        value = combine(value, device::device(i as {index_type}));
+        i += 1;
+    }
+    reduce_block(scratch, output, value);
+}
+"#;
+
+pub(super) static CHUNK: &str = r#"
+/// This thread's contiguous chunk of `0..size`. Contiguous chunks keep the
+/// partial results in order, so the operator only has to be associative.
+unsafe fn chunk(size: usize) -> (usize, usize) {
+    let thread_id: usize = _thread_idx_x() as usize;
+    let block_id: usize = _block_idx_x() as usize;
+    
+    let block_dim: usize = _block_dim_x() as usize;
+    let grid_dim: usize = _grid_dim_x() as usize;
+    
+    let n_threads: usize = block_dim * grid_dim;
+    let thread_index: usize =  thread_id + block_id * block_dim;
+
+    let chunk: usize = size.div_ceil(n_threads);
+    let start: usize = thread_index.saturating_mul(chunk).min(size);
+    let end: usize = start.saturating_add(chunk).min(size);
+    (start, end)
+}
+"#;
+
+/// rustc cannot place statics in `.shared` memory, so the block-level tree
+/// runs in `scratch`, which holds `block_dim` values per block, with
+/// `_syncthreads` making each level visible to the next.
+static REDUCE_BLOCK: &str = r#"
+/// Combines every thread's `value` in this block into `output[block_id]`.
+unsafe fn reduce_block(scratch: *mut {item_type}, output: *mut {item_type}, value: {item_type}) {
+    let thread_id: usize = _thread_idx_x() as usize;
+    let block_id: usize = _block_idx_x() as usize;
+    let block_dim: usize = _block_dim_x() as usize;
+
+    let block = scratch.add(block_id * block_dim);
+    block.add(thread_id).write_volatile(value);
+    _syncthreads();
+    let mut stride: usize = 1;
+    while stride < block_dim {
+        if thread_id % (2 * stride) == 0 && thread_id + stride < block_dim {
+            let left = block.add(thread_id).read_volatile();
+            let right = block.add(thread_id + stride).read_volatile();
+            block.add(thread_id).write_volatile(combine(left, right));
+        }
+        _syncthreads();
+        stride *= 2;
+    }
+    if thread_id == 0 {
+        *output.add(block_id) = block.read_volatile();
+    }
+}
+"#;
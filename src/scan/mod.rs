@@ -0,0 +1,90 @@
+use crate::range::PRELUDE;
+use crate::reduce::CHUNK;
+
+pub(super) fn lib_rs(item_type: &str, exclusive: bool) -> String {
+    format!("{PRELUDE}{LIB_RS}{CHUNK}")
+        .replace("{item_type}", item_type)
+        .replace("{exclusive}", &exclusive.to_string())
+}
+
+/// A two-phase scan. In phase 0 each block scans its tile into `output`,
+/// offsetting every thread's chunk by the chunks before it in the block, and
+/// writes the block's total to `sums`. The host then replaces `sums` with each
+/// block's exclusive prefix, and phase 1 combines it into the block's tile.
+/// As in reductions, the block-level scan runs in global `scratch`.
+static LIB_RS: &str = r#"
+use device::device as combine;
+
+const EXCLUSIVE: bool = {exclusive};
+
+#[no_mangle]
+pub unsafe extern "ptx-kernel" fn kernel(
+    input: *const {item_type},
+    output: *mut {item_type},
+    scratch: *mut {item_type},
+    sums: *mut {item_type},
+    size: usize,
+    identity: {item_type},
+    phase: u32,
+)
+{
+    let thread_id: usize = _thread_idx_x() as usize;
+    let block_id: usize = _block_idx_x() as usize;
+    let block_dim: usize = _block_dim_x() as usize;
+    let (start, end) = chunk(size);
+
+    if phase == 1 {
+        if block_id > 0 {
+            let offset = *sums.add(block_id);
+            let mut i: usize = start;
+            while i < end {
+                *output.add(i) = combine(offset, *output.add(i));
+                i += 1;
+            }
+        }
+        return;
+    }
+
+    let mut total = identity;
+    let mut i: usize = start;
+    while i < end {
+        let value = combine(total, *input.add(i));
+        *output.add(i) = if EXCLUSIVE { total } else { value };
+        total = value;
+        i += 1;
+    }
+
+    // an inclusive scan of the threads' totals, read before and written
+    // after each barrier so that no thread sees a half-updated level
+    let block = scratch.add(block_id * block_dim);
+    block.add(thread_id).write_volatile(total);
+    _syncthreads();
+    let mut stride: usize = 1;
+    while stride < block_dim {
+        let current = block.add(thread_id).read_volatile();
+        let left = if thread_id >= stride {
+            Some(block.add(thread_id - stride).read_volatile())
+        } else {
+            None
+        };
+        _syncthreads();
+        if let Some(left) = left {
+            block.add(thread_id).write_volatile(combine(left, current));
+        }
+        _syncthreads();
+        stride *= 2;
+    }
+
+    if thread_id > 0 {
+        let offset = block.add(thread_id - 1).read_volatile();
+        let mut i: usize = start;
+        while i < end {
+            *output.add(i) = combine(offset, *output.add(i));
+            i += 1;
+        }
+    }
+    if thread_id == block_dim - 1 {
+        *sums.add(block_id) = block.add(thread_id).read_volatile();
+    }
+}
+"#;
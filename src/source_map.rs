@@ -0,0 +1,77 @@
+use proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+
+/// The text written to a kernel crate's `src/device.rs`, together with the
+/// byte range of every token in it, so that rustc diagnostics on the written
+/// file can be pointed back at the tokens the user wrote.
+#[derive(Debug, Default)]
+pub(super) struct SourceMap {
+    text: String,
+    tokens: Vec<(usize, usize, Span)>,
+    joint: bool,
+}
+
+impl SourceMap {
+    pub(super) fn new(tokens: TokenStream) -> Self {
+        let mut map = Self::default();
+        map.push_stream(tokens);
+        map.text.push('\n');
+        map
+    }
+
+    pub(super) fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Spans the tokens overlapping `start..end`, as a stream whose first and
+    /// last tokens carry the spans of the first and last overlapping tokens.
+    pub(super) fn spanned(&self, start: usize, end: usize) -> Option<TokenStream> {
+        let end = end.max(start + 1);
+        let mut overlapping = self.tokens.iter()
+            .filter(|(token_start, token_end, _)| *token_start < end && start < *token_end)
+            .map(|(_, _, span)| *span);
+        let first = overlapping.next()?;
+        let last = overlapping.next_back().unwrap_or(first);
+        Some([first, last].into_iter().map(|span| {
+            let mut punct = proc_macro2::Punct::new('.', Spacing::Alone);
+            punct.set_span(span);
+            TokenTree::Punct(punct)
+        }).collect())
+    }
+
+    fn push_stream(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, group.span_open(), false);
+                    self.push_stream(group.stream());
+                    self.push(close, group.span_close(), false);
+                },
+                TokenTree::Punct(punct) => {
+                    let joint = punct.spacing() == Spacing::Joint;
+                    self.push(&punct.to_string(), punct.span(), joint);
+                },
+                token => self.push(&token.to_string(), token.span(), false),
+            }
+        }
+    }
+
+    fn push(&mut self, token: &str, span: Span, joint: bool) {
+        if token.is_empty() {
+            return;
+        }
+        // joint punctuation such as `->` or the `'` of a lifetime must stay glued
+        if !self.text.is_empty() && !self.joint {
+            self.text.push(' ');
+        }
+        let start = self.text.len();
+        self.text.push_str(token);
+        self.tokens.push((start, self.text.len(), span));
+        self.joint = joint;
+    }
+}
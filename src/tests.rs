@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use proc_macro2::TokenStream;
+
+use crate::*;
+
+/// Records each kernel crate's files instead of building it, so that the
+/// emitted code can be checked without CUDA or the nvptx target. Kernel
+/// crates use the pinned toolchain and a home of their own, which holds only
+/// the device items emitted through the same `Snapshot`, whatever the
+/// environment says.
+struct Snapshot {
+    home: PathBuf,
+    files: RefCell<Vec<(PathBuf, String)>>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let home = std::env::temp_dir().join(format!(
+            "spindle-snapshot-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed),
+        ));
+        // left over from an earlier run with the same process id
+        let _ = std::fs::remove_dir_all(&home);
+        Self { home, files: RefCell::default() }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.home);
+    }
+}
+
+impl Kernels for Snapshot {
+    fn ptx(&self, attr: &RangeAttributes, item: &impl KernelFn, lib_rs: String) -> Result<Option<TokenStream>, TokenStream> {
+        let krate = KernelCrate::new(self.home.clone(), toolchain::PINNED.to_string(), attr, item, lib_rs)?;
+        let source_map = krate.source_map();
+        let files = krate.files(source_map.text())
+            .map(|(path, contents)| (path, contents.to_string()));
+        self.files.borrow_mut().extend(files);
+        Ok(Some(quote::quote! { Ptx::from_src("kernel.ptx") }))
+    }
+
+    fn home(&self, _attr: &RangeAttributes) -> Option<PathBuf> {
+        Some(self.home.clone())
+    }
+}
+
+/// The host code `emit` generates, followed by the kernel crate's files
+/// (only those under `src/`, unless `all_files`).
+fn expand(all_files: bool, emit: impl FnOnce(&Snapshot) -> TokenResult) -> String {
+    let snapshot = Snapshot::default();
+    let tokens = emit(&snapshot).unwrap_or_else(|err| panic!("emit failed: {err}"));
+    let host = syn::parse2(tokens).expect("emitted host code parses as a file");
+    let mut expanded = prettyplease::unparse(&host);
+    for (path, contents) in snapshot.files.take() {
+        if all_files || path.starts_with("src") {
+            expanded.push_str(&format!("\n// {}\n{}\n", path.display(), contents.trim()));
+        }
+    }
+    expanded
+}
+
+#[test]
+fn range_kernel() {
+    insta::assert_snapshot!(expand(true, |kernels| emit_range_kernel(
+        syn::parse_quote! {},
+        syn::parse_quote! { fn square(x: u64) -> f32 { (x * x) as f32 } },
+        kernels,
+    )));
+}
+
+#[test]
+fn range_kernel_with_launch_dims() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_range_kernel(
+        syn::parse_quote! { block_dim = 128, grid_dim = 64, ptx = "file" },
+        syn::parse_quote! { fn cube(x: i8) -> i64 { (x as i64).pow(3) } },
+        kernels,
+    )));
+}
+
+#[test]
+fn range_reduce_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_range_kernel(
+        syn::parse_quote! { reduce = add },
+        syn::parse_quote! { fn quarter(x: u32) -> f64 { x as f64 / 4.0 } },
+        kernels,
+    )));
+}
+
+#[test]
+fn range_tuple_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_range_kernel(
+        syn::parse_quote! {},
+        syn::parse_quote! { fn polar(i: u32) -> (f32, u8) { (i as f32, i as u8) } },
+        kernels,
+    )));
+}
+
+#[test]
+fn range_array_soa_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_range_kernel(
+        syn::parse_quote! { layout = "soa" },
+        syn::parse_quote! { fn quad(i: i32) -> [u16; 3] { [i as u16, 1, 2] } },
+        kernels,
+    )));
+}
+
+#[test]
+fn range_struct_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| {
+        let device = emit_device_item(
+            syn::parse_quote! {},
+            syn::parse_quote! {
+                #[derive(Clone, Copy)]
+                #[repr(C)]
+                struct Point { pub x: f32, pub y: u8 }
+            },
+            kernels,
+        )?;
+        let kernel = emit_range_kernel(
+            syn::parse_quote! {},
+            syn::parse_quote! { fn point(i: u32) -> Point { Point { x: i as f32, y: i as u8 } } },
+            kernels,
+        )?;
+        Ok(quote::quote! { #device #kernel })
+    }));
+}
+
+#[test]
+fn range_kernel_with_uses() {
+    insta::assert_snapshot!(expand(false, |kernels| {
+        let scale = emit_device_item(
+            syn::parse_quote! {},
+            syn::parse_quote! { const SCALE: f32 = 0.5; },
+            kernels,
+        )?;
+        let scaled = emit_device_item(
+            syn::parse_quote! { uses = [SCALE] },
+            syn::parse_quote! { fn scaled(x: f32) -> f32 { x * SCALE } },
+            kernels,
+        )?;
+        let kernel = emit_range_kernel(
+            syn::parse_quote! { uses = [scaled] },
+            syn::parse_quote! { fn half(i: u32) -> f32 { scaled(i as f32) } },
+            kernels,
+        )?;
+        Ok(quote::quote! { #scale #scaled #kernel })
+    }));
+}
+
+#[test]
+fn map_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_map_kernel(
+        syn::parse_quote! {},
+        syn::parse_quote! { fn halve(x: f32) -> f64 { x as f64 / 2.0 } },
+        kernels,
+    )));
+}
+
+#[test]
+fn in_place_map_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_map_kernel(
+        syn::parse_quote! {},
+        syn::parse_quote! { fn step(x: &mut f32) { *x += 1.0 } },
+        kernels,
+    )));
+}
+
+#[test]
+fn zip_map_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_map_kernel(
+        syn::parse_quote! {},
+        syn::parse_quote! { fn axpy(#[broadcast] a: f32, x: f32, y: f32) -> f32 { a * x + y } },
+        kernels,
+    )));
+}
+
+#[test]
+fn reduce_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_reduce_kernel(
+        syn::parse_quote! { identity = i64::MIN },
+        syn::parse_quote! { fn biggest(a: i64, b: i64) -> i64 { a.max(b) } },
+        kernels,
+    )));
+}
+
+#[test]
+fn scan_kernel() {
+    insta::assert_snapshot!(expand(false, |kernels| emit_scan_kernel(
+        syn::parse_quote! { identity = 0, exclusive = true },
+        syn::parse_quote! { fn offsets(a: u32, b: u32) -> u32 { a + b } },
+        kernels,
+    )));
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::process::Command;
+
+static TARGET: &str = "nvptx64-nvidia-cuda";
+/// The nightly kernel crates are built with unless one is configured. Later
+/// nightlies removed the `stdsimd` feature the kernel prelude enables.
+pub(super) static PINNED: &str = "nightly-2024-01-31";
+
+/// Whether `toolchain` names a nightly, as the kernel crate's `-Z` flags and
+/// features need.
+pub(super) fn is_nightly(toolchain: &str) -> bool {
+    toolchain == "nightly" || toolchain.starts_with("nightly-")
+}
+
+/// The `toolchain` in the `[package.metadata.spindle]` table of `manifest`,
+/// if it sets one.
+pub(super) fn from_metadata(manifest: &Path) -> Result<Option<String>, String> {
+    let Ok(contents) = std::fs::read_to_string(manifest) else {
+        return Ok(None)
+    };
+    let table: toml::Table = contents.parse()
+        .map_err(|err| format!("failed to parse `{}`: {err}", manifest.display()))?;
+    let toolchain = table.get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("spindle"))
+        .and_then(|spindle| spindle.get("toolchain"));
+    match toolchain {
+        None => Ok(None),
+        Some(toml::Value::String(toolchain)) => Ok(Some(toolchain.clone())),
+        Some(_) => Err(format!("`package.metadata.spindle.toolchain` in `{}` is not a string", manifest.display())),
+    }
+}
+
+/// Checks that the `channel` toolchain can build kernel crates, before cargo
+/// fails less helpfully, and names the first missing piece and its install
+/// command. Kernel crates are `no_std` and link the `core` that rustup ships
+/// with the nvptx target, so they need no `rust-src` to build it.
+pub(super) fn preflight(channel: &str) -> Result<(), String> {
+    if stdout(Command::new("rustc").arg(format!("+{channel}")).arg("--version")).is_none() {
+        if stdout(Command::new("rustup").arg("--version")).is_none() {
+            return Err(format!(
+                "kernels are built with `cargo +{channel}`, which needs rustup; install it from https://rustup.rs"
+            ))
+        }
+        return Err(format!(
+            "the `{channel}` toolchain is not installed; install it with `rustup toolchain install {channel}`"
+        ))
+    }
+    // rustup lists installed targets, while rustc only knows which it supports
+    let targets = stdout(Command::new("rustup").args(["target", "list", "--installed", "--toolchain", channel]))
+        .or_else(|| stdout(Command::new("rustc").arg(format!("+{channel}")).args(["--print", "target-list"])))
+        .unwrap_or_default();
+    if !targets.lines().any(|target| target.trim() == TARGET) {
+        return Err(format!(
+            "the `{channel}` toolchain lacks the `{TARGET}` target; install it with `rustup target add {TARGET} --toolchain {channel}`"
+        ))
+    }
+    Ok(())
+}
+
+fn stdout(command: &mut Command) -> Option<String> {
+    // a probe must report a missing toolchain, not install it
+    command.env("RUSTUP_AUTO_INSTALL", "0")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,11 @@
+mod a {
+    #[spindle_macros::device]
+    pub const SCALE: f32 = 1.0;
+}
+
+mod b {
+    #[spindle_macros::device]
+    pub const SCALE: f32 = 2.0;
+}
+
+fn main() {}
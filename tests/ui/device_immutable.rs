@@ -0,0 +1,4 @@
+#[spindle_macros::device]
+static mut COUNTER: u32 = 0;
+
+fn main() {}
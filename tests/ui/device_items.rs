@@ -0,0 +1,7 @@
+#[spindle_macros::device]
+enum Direction {
+    Up,
+    Down,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+#[spindle_macros::device]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+fn main() {}
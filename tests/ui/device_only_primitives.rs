@@ -0,0 +1,8 @@
+#[spindle_macros::device]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Named {
+    name: &'static str,
+}
+
+fn main() {}
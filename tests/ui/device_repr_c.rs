@@ -0,0 +1,8 @@
+#[spindle_macros::device]
+#[derive(Clone, Copy)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range(block_dim = 256, threads_per_block = 128)]
+fn square(x: u32) -> u32 {
+    x * x
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range]
+fn product(x: u32, y: u32) -> u32 {
+    x * y
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::scan(identity = 0, exclusive = 1)]
+fn add(a: u32, b: u32) -> u32 {
+    a + b
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range(layout = "columns")]
+fn pair(x: u32) -> (u32, u32) {
+    (x, x)
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::map]
+fn constant() -> f32 {
+    1.0
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::map]
+fn double(x: &mut f32) -> f32 {
+    *x * 2.0
+}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[spindle_macros::map]
+fn nothing(_x: f32) {}
+
+fn main() {}
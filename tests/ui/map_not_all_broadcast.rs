@@ -0,0 +1,6 @@
+#[spindle_macros::map]
+fn scale(#[broadcast] a: f32, #[broadcast] b: f32) -> f32 {
+    a * b
+}
+
+fn main() {}
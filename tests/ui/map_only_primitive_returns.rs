@@ -0,0 +1,6 @@
+#[spindle_macros::map]
+fn pair(x: f32) -> (f32, f32) {
+    (x, x)
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::map]
+fn length(x: String) -> usize {
+    x.len()
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[spindle_macros::basic_range]
+#[inline]
+fn square(x: u32) -> u32 {
+    x * x
+}
+
+fn main() {}
@@ -0,0 +1,4 @@
+#[spindle_macros::basic_range]
+fn nothing(_x: u32) {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range]
+fn square(x: u32) -> u32 where u32: Copy {
+    x * x
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+struct Square;
+
+impl Square {
+    #[spindle_macros::basic_range]
+    fn square(&self) -> u32 {
+        0
+    }
+}
+
+fn main() {}
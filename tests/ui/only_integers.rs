@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range]
+fn half(x: f32) -> f32 {
+    x / 2.0
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range]
+fn boxed(x: u32) -> Box<u32> {
+    Box::new(x)
+}
+
+fn main() {}
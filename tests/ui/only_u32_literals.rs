@@ -0,0 +1,8 @@
+const THREADS: u32 = 256;
+
+#[spindle_macros::basic_range(block_dim = THREADS)]
+fn square(x: u32) -> u32 {
+    x * x
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::basic_range(ptx = "inline")]
+fn square(x: u32) -> u32 {
+    x * x
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[spindle_macros::reduce(identity = false)]
+fn or(a: bool, b: bool) -> bool {
+    a || b
+}
+
+fn main() {}
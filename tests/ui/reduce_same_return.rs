@@ -0,0 +1,6 @@
+#[spindle_macros::scan(identity = 0)]
+fn add(a: u32, b: u32) -> u64 {
+    (a + b) as u64
+}
+
+fn main() {}
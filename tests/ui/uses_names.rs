@@ -0,0 +1,6 @@
+#[spindle_macros::map(uses = helper)]
+fn double(x: f32) -> f32 {
+    x * 2.0
+}
+
+fn main() {}